@@ -6,7 +6,10 @@
 
 use hyper::{HeaderMap, Method, StatusCode};
 
+use super::policy::{CachePolicy, CachePolicyTTL};
+use super::vary::{CacheVary, CacheVarySpec};
 use crate::header::response_ignore::HeaderResponseBloomResponseIgnore;
+use crate::header::response_ttl::HeaderResponseBloomResponseTTL;
 
 pub struct CacheCheck;
 
@@ -15,10 +18,15 @@ impl CacheCheck {
         Self::is_cacheable_method(method) == true
     }
 
-    pub fn from_response(method: &Method, status: &StatusCode, headers: &HeaderMap) -> bool {
-        Self::is_cacheable_method(method) == true
+    pub fn from_response(
+        method: &Method,
+        status: &StatusCode,
+        headers: &HeaderMap,
+        honor_cache_control: bool,
+    ) -> bool {
+        Self::is_storable_method(method) == true
             && Self::is_cacheable_status(status) == true
-            && Self::is_cacheable_response(headers) == true
+            && Self::is_cacheable_response(headers, honor_cache_control) == true
     }
 
     fn is_cacheable_method(method: &Method) -> bool {
@@ -28,13 +36,18 @@ impl CacheCheck {
         }
     }
 
+    fn is_storable_method(method: &Method) -> bool {
+        // Notice: 'HEAD' requests are served from 'GET' responses, though \
+        //   their own responses cannot be stored (as they have no body).
+        matches!(*method, Method::GET | Method::OPTIONS)
+    }
+
     fn is_cacheable_status(status: &StatusCode) -> bool {
         match *status {
             StatusCode::OK
             | StatusCode::NON_AUTHORITATIVE_INFORMATION
             | StatusCode::NO_CONTENT
             | StatusCode::RESET_CONTENT
-            | StatusCode::PARTIAL_CONTENT
             | StatusCode::MULTI_STATUS
             | StatusCode::ALREADY_REPORTED
             | StatusCode::MULTIPLE_CHOICES
@@ -62,12 +75,32 @@ impl CacheCheck {
         }
     }
 
-    fn is_cacheable_response(headers: &HeaderMap) -> bool {
+    fn is_cacheable_response(headers: &HeaderMap, honor_cache_control: bool) -> bool {
         // Ignore responses with 'Bloom-Response-Ignore'
-        headers
+        if headers
             .get(HeaderResponseBloomResponseIgnore::header_name())
             .and_then(|value| HeaderResponseBloomResponseIgnore::from_header_value(value))
-            .is_none()
+            .is_some()
+        {
+            return false;
+        }
+
+        // Ignore responses that vary on anything (ie. 'Vary: *'), as no \
+        //   further request can be known to match them
+        if CacheVary::from_response(headers, false) == CacheVarySpec::Wildcard {
+            return false;
+        }
+
+        // Ignore responses that upstream caching policy forbids to cache \
+        //   (if honored), unless 'Bloom-Response-TTL' is set, as it takes \
+        //   precedence over upstream caching policy
+        if honor_cache_control == true
+            && headers.contains_key(HeaderResponseBloomResponseTTL::header_name()) == false
+        {
+            return CachePolicy::from_response(headers) != CachePolicyTTL::Uncacheable;
+        }
+
+        true
     }
 }
 
@@ -77,41 +110,86 @@ mod tests {
 
     #[test]
     fn it_asserts_valid_cacheable_method() {
-        assert_eq!(CacheCheck::is_cacheable_method(&Method::GET), true, "GET");
-        assert_eq!(CacheCheck::is_cacheable_method(&Method::HEAD), true, "HEAD");
-        assert_eq!(
-            CacheCheck::is_cacheable_method(&Method::OPTIONS),
-            true,
-            "OPTIONS"
-        );
-        assert_eq!(
-            CacheCheck::is_cacheable_method(&Method::POST),
-            false,
-            "POST"
-        );
+        assert!(CacheCheck::is_cacheable_method(&Method::GET), "GET");
+        assert!(CacheCheck::is_cacheable_method(&Method::HEAD), "HEAD");
+        assert!(CacheCheck::is_cacheable_method(&Method::OPTIONS), "OPTIONS");
+        assert!(!CacheCheck::is_cacheable_method(&Method::POST), "POST");
+    }
+
+    #[test]
+    fn it_asserts_valid_storable_method() {
+        assert!(CacheCheck::is_storable_method(&Method::GET), "GET");
+        assert!(!CacheCheck::is_storable_method(&Method::HEAD), "HEAD");
+        assert!(CacheCheck::is_storable_method(&Method::OPTIONS), "OPTIONS");
     }
 
     #[test]
     fn it_asserts_valid_cacheable_status() {
-        assert_eq!(
-            CacheCheck::is_cacheable_status(&StatusCode::OK),
-            true,
-            "200 OK"
-        );
-        assert_eq!(
+        assert!(CacheCheck::is_cacheable_status(&StatusCode::OK), "200 OK");
+        assert!(
             CacheCheck::is_cacheable_status(&StatusCode::UNAUTHORIZED),
-            true,
             "401 OK"
         );
-        assert_eq!(
-            CacheCheck::is_cacheable_status(&StatusCode::BAD_REQUEST),
-            false,
+        assert!(
+            !CacheCheck::is_cacheable_status(&StatusCode::PARTIAL_CONTENT),
+            "206 Partial Content"
+        );
+        assert!(
+            !CacheCheck::is_cacheable_status(&StatusCode::BAD_REQUEST),
             "400 Bad Request"
         );
-        assert_eq!(
-            CacheCheck::is_cacheable_status(&StatusCode::INTERNAL_SERVER_ERROR),
-            false,
+        assert!(
+            !CacheCheck::is_cacheable_status(&StatusCode::INTERNAL_SERVER_ERROR),
             "500 Internal Server Error"
         );
     }
+
+    #[test]
+    fn it_asserts_valid_cacheable_response() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            hyper::header::CACHE_CONTROL,
+            hyper::header::HeaderValue::from_static("private"),
+        );
+
+        assert!(
+            CacheCheck::is_cacheable_response(&headers, false),
+            "private (not honored)"
+        );
+        assert!(
+            !CacheCheck::is_cacheable_response(&headers, true),
+            "private (honored)"
+        );
+
+        headers.insert(
+            HeaderResponseBloomResponseTTL::header_name(),
+            hyper::header::HeaderValue::from_static("60"),
+        );
+
+        assert!(
+            CacheCheck::is_cacheable_response(&headers, true),
+            "private with ttl (honored)"
+        );
+
+        headers.insert(
+            hyper::header::VARY,
+            hyper::header::HeaderValue::from_static("*"),
+        );
+
+        assert!(
+            !CacheCheck::is_cacheable_response(&headers, false),
+            "vary any"
+        );
+
+        headers.insert(
+            HeaderResponseBloomResponseIgnore::header_name(),
+            hyper::header::HeaderValue::from_static("1"),
+        );
+
+        assert!(
+            !CacheCheck::is_cacheable_response(&headers, false),
+            "ignore"
+        );
+    }
 }
@@ -0,0 +1,334 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::io::{self, Write};
+
+use brotli::enc::BrotliEncoderParams;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::header::{self, HeaderMap};
+use hyper::StatusCode;
+
+use super::policy::CachePolicy;
+
+pub struct CacheEncoding;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheEncodingValue {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+// Encodings in order of preference, if a client accepts several of them \
+//   with the same quality (the smaller the encoded body, the better)
+static ENCODING_PREFERENCE: [CacheEncodingValue; 3] = [
+    CacheEncodingValue::Brotli,
+    CacheEncodingValue::Zstd,
+    CacheEncodingValue::Gzip,
+];
+
+static ENCODING_IDENTITY: &'static str = "identity";
+static ENCODING_WILDCARD: &'static str = "*";
+
+const ENCODING_LEVEL_BROTLI: i32 = 5;
+const ENCODING_LEVEL_ZSTD: i32 = 3;
+const ENCODING_LEVEL_GZIP: u32 = 6;
+
+impl CacheEncodingValue {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            CacheEncodingValue::Brotli => "br",
+            CacheEncodingValue::Zstd => "zstd",
+            CacheEncodingValue::Gzip => "gzip",
+        }
+    }
+}
+
+impl CacheEncoding {
+    pub fn negotiate(headers: &HeaderMap) -> Option<CacheEncodingValue> {
+        let accepted = Self::parse_accept(headers);
+
+        // Pick the encoding with the highest quality (ties are broken by \
+        //   order of preference), unless the client prefers identity
+        let mut best: Option<(CacheEncodingValue, f32)> = None;
+
+        for encoding in ENCODING_PREFERENCE.iter() {
+            let quality = Self::find_quality(&accepted, encoding.to_str());
+
+            let is_better = match best {
+                Some((_, best_quality)) => quality > best_quality,
+                None => true,
+            };
+
+            if quality > 0.0 && is_better == true {
+                best = Some((*encoding, quality));
+            }
+        }
+
+        let (encoding, quality) = best?;
+
+        match Self::find_explicit_quality(&accepted, ENCODING_IDENTITY) {
+            Some(identity_quality) if identity_quality > quality => None,
+            _ => Some(encoding),
+        }
+    }
+
+    pub fn normalize_accept(headers: &HeaderMap) -> String {
+        // Notice: responses that the API encoded itself get cached per set \
+        //   of accepted encodings, so that clients that accept the same \
+        //   encodings share the same cached responses.
+        let accepted = Self::parse_accept(headers);
+
+        ENCODING_PREFERENCE
+            .iter()
+            .map(|encoding| encoding.to_str())
+            .filter(|name| Self::find_quality(&accepted, name) > 0.0)
+            .collect::<Vec<&str>>()
+            .join(",")
+    }
+
+    pub fn is_encoded(headers: &HeaderMap) -> bool {
+        headers
+            .get(header::CONTENT_ENCODING)
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(|value| value.trim().eq_ignore_ascii_case(ENCODING_IDENTITY) == false)
+                    .unwrap_or(true)
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn is_compressible(status: &StatusCode, headers: &HeaderMap) -> bool {
+        // Notice: responses that forbid transformations must be served \
+        //   as-is (RFC 9111, section 5.2.2.6)
+        if status.is_informational() == true
+            || *status == StatusCode::NO_CONTENT
+            || *status == StatusCode::PARTIAL_CONTENT
+            || *status == StatusCode::NOT_MODIFIED
+            || Self::is_encoded(headers) == true
+            || CachePolicy::has_no_transform(headers) == true
+        {
+            return false;
+        }
+
+        headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(Self::is_compressible_type)
+            .unwrap_or(false)
+    }
+
+    pub fn encode(encoding: CacheEncodingValue, body: &[u8]) -> io::Result<Bytes> {
+        let encoded = match encoding {
+            CacheEncodingValue::Brotli => {
+                let mut encoded = Vec::new();
+
+                let params = BrotliEncoderParams {
+                    quality: ENCODING_LEVEL_BROTLI,
+                    ..Default::default()
+                };
+
+                brotli::BrotliCompress(&mut &body[..], &mut encoded, &params)?;
+
+                encoded
+            }
+            CacheEncodingValue::Zstd => zstd::encode_all(body, ENCODING_LEVEL_ZSTD)?,
+            CacheEncodingValue::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(ENCODING_LEVEL_GZIP));
+
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+        };
+
+        Ok(Bytes::from(encoded))
+    }
+
+    fn is_compressible_type(content_type: &str) -> bool {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        mime.starts_with("text/")
+            || mime.ends_with("+json")
+            || mime.ends_with("+xml")
+            || mime == "application/json"
+            || mime == "application/javascript"
+            || mime == "application/xml"
+            || mime == "image/svg+xml"
+    }
+
+    fn parse_accept(headers: &HeaderMap) -> Vec<(String, f32)> {
+        headers
+            .get_all(header::ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut parts = coding.split(';');
+
+                let name = parts.next()?.trim().to_lowercase();
+
+                if name.is_empty() == true {
+                    return None;
+                }
+
+                // Notice: codings with an invalid quality are ignored
+                let quality = match parts
+                    .map(|param| param.trim())
+                    .find_map(|param| param.strip_prefix("q="))
+                {
+                    Some(quality) => quality.trim().parse::<f32>().ok()?,
+                    None => 1.0,
+                };
+
+                Some((name, quality))
+            })
+            .collect()
+    }
+
+    fn find_explicit_quality(accepted: &[(String, f32)], name: &str) -> Option<f32> {
+        accepted
+            .iter()
+            .find(|(accepted_name, _)| accepted_name == name)
+            .map(|(_, quality)| *quality)
+    }
+
+    fn find_quality(accepted: &[(String, f32)], name: &str) -> f32 {
+        Self::find_explicit_quality(accepted, name)
+            .or_else(|| Self::find_explicit_quality(accepted, ENCODING_WILDCARD))
+            .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use hyper::header::HeaderValue;
+
+    fn make_headers(accept_encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static(accept_encoding),
+        );
+
+        headers
+    }
+
+    #[test]
+    fn it_negotiates_encoding() {
+        assert_eq!(CacheEncoding::negotiate(&HeaderMap::new()), None);
+        assert_eq!(
+            CacheEncoding::negotiate(&make_headers("gzip, deflate, br")),
+            Some(CacheEncodingValue::Brotli)
+        );
+        assert_eq!(
+            CacheEncoding::negotiate(&make_headers("br;q=0.5, gzip")),
+            Some(CacheEncodingValue::Gzip)
+        );
+        assert_eq!(
+            CacheEncoding::negotiate(&make_headers("zstd, br;q=0")),
+            Some(CacheEncodingValue::Zstd)
+        );
+        assert_eq!(
+            CacheEncoding::negotiate(&make_headers("*")),
+            Some(CacheEncodingValue::Brotli)
+        );
+        assert_eq!(
+            CacheEncoding::negotiate(&make_headers("gzip;q=0.5, identity")),
+            None
+        );
+        assert_eq!(CacheEncoding::negotiate(&make_headers("deflate")), None);
+    }
+
+    #[test]
+    fn it_normalizes_accepted_encodings() {
+        assert_eq!(
+            CacheEncoding::normalize_accept(&make_headers("gzip, deflate, br")),
+            "br,gzip"
+        );
+        assert_eq!(
+            CacheEncoding::normalize_accept(&make_headers("br, gzip;q=0.8")),
+            "br,gzip"
+        );
+        assert_eq!(CacheEncoding::normalize_accept(&HeaderMap::new()), "");
+    }
+
+    #[test]
+    fn it_checks_compressible_response() {
+        let mut headers = HeaderMap::new();
+
+        assert!(!CacheEncoding::is_compressible(&StatusCode::OK, &headers));
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+
+        assert!(CacheEncoding::is_compressible(&StatusCode::OK, &headers));
+        assert!(!CacheEncoding::is_compressible(
+            &StatusCode::NO_CONTENT,
+            &headers
+        ));
+
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+        assert!(!CacheEncoding::is_compressible(&StatusCode::OK, &headers));
+
+        headers.remove(header::CONTENT_ENCODING);
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("public, no-transform"),
+        );
+
+        assert!(!CacheEncoding::is_compressible(&StatusCode::OK, &headers));
+    }
+
+    #[test]
+    fn it_encodes_body() {
+        let body = "{\"hello\": \"world\"}".repeat(64);
+
+        let mut decoded_gzip = String::new();
+
+        GzDecoder::new(
+            &CacheEncoding::encode(CacheEncodingValue::Gzip, body.as_bytes()).unwrap()[..],
+        )
+        .read_to_string(&mut decoded_gzip)
+        .unwrap();
+
+        assert_eq!(decoded_gzip, body);
+
+        let mut decoded_brotli = Vec::new();
+
+        brotli::BrotliDecompress(
+            &mut &CacheEncoding::encode(CacheEncodingValue::Brotli, body.as_bytes()).unwrap()[..],
+            &mut decoded_brotli,
+        )
+        .unwrap();
+
+        assert_eq!(decoded_brotli, body.as_bytes());
+
+        assert_eq!(
+            zstd::decode_all(
+                &CacheEncoding::encode(CacheEncodingValue::Zstd, body.as_bytes()).unwrap()[..]
+            )
+            .unwrap(),
+            body.as_bytes()
+        );
+    }
+}
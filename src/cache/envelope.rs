@@ -0,0 +1,248 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::convert::TryInto;
+use std::str;
+
+use bytes::{BufMut, Bytes};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+
+use super::encoding::CacheEncodingValue;
+
+pub struct CacheEnvelope {
+    pub status: StatusCode,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Bytes,
+    pub encoding: Option<CacheEncodingValue>,
+    pub modified: Option<u64>,
+}
+
+pub static ENVELOPE_VERSION: &'static [u8] = "1".as_bytes();
+
+const ENVELOPE_LENGTH_SIZE: usize = 4;
+
+impl CacheEnvelope {
+    pub fn new(status: StatusCode, headers: &HeaderMap, body: Bytes) -> CacheEnvelope {
+        CacheEnvelope {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            body,
+            encoding: None,
+            modified: None,
+        }
+    }
+
+    pub fn update_headers(&mut self, headers: &HeaderMap) {
+        // Replace stored headers with updated ones, keeping the stored \
+        //   'Content-Length' header as it relates to the stored body \
+        //   (RFC 9111, section 4.3.4)
+        self.headers.retain(|(name, _)| {
+            name == header::CONTENT_LENGTH || headers.contains_key(name) == false
+        });
+
+        for (name, value) in headers {
+            if name != header::CONTENT_LENGTH {
+                self.headers.push((name.to_owned(), value.to_owned()));
+            }
+        }
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        self.headers.iter().cloned().collect()
+    }
+
+    pub fn encode_status(&self) -> Vec<u8> {
+        self.status.as_str().as_bytes().to_vec()
+    }
+
+    pub fn encode_headers(&self) -> Vec<u8> {
+        // Encode headers as an ordered list of length-prefixed name and value \
+        //   pairs, so that binary header values and repeated header names \
+        //   are stored exactly as they were received.
+        let mut encoded = Vec::with_capacity(
+            ENVELOPE_LENGTH_SIZE
+                + self
+                    .headers
+                    .iter()
+                    .map(|(name, value)| {
+                        2 * ENVELOPE_LENGTH_SIZE + name.as_str().len() + value.len()
+                    })
+                    .sum::<usize>(),
+        );
+
+        encoded.put_u32(self.headers.len() as u32);
+
+        for (name, value) in &self.headers {
+            encoded.put_u32(name.as_str().len() as u32);
+            encoded.put_slice(name.as_str().as_bytes());
+            encoded.put_u32(value.len() as u32);
+            encoded.put_slice(value.as_bytes());
+        }
+
+        encoded
+    }
+
+    pub fn decode(status: &[u8], headers: &[u8], body: Bytes) -> Option<CacheEnvelope> {
+        Some(CacheEnvelope {
+            status: Self::decode_status(status)?,
+            headers: Self::decode_headers(headers)?,
+            body,
+            encoding: None,
+            modified: None,
+        })
+    }
+
+    fn decode_status(status: &[u8]) -> Option<StatusCode> {
+        str::from_utf8(status)
+            .ok()
+            .and_then(|status| status.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+    }
+
+    fn decode_headers(headers: &[u8]) -> Option<Vec<(HeaderName, HeaderValue)>> {
+        let mut cursor = headers;
+
+        let count = Self::read_length(&mut cursor)?;
+
+        // Notice: do not trust the stored count to pre-allocate, as a \
+        //   corrupted value could lead to a huge allocation.
+        let mut decoded = Vec::new();
+
+        for _ in 0..count {
+            let name = Self::read_chunk(&mut cursor)?;
+            let value = Self::read_chunk(&mut cursor)?;
+
+            decoded.push((
+                HeaderName::from_bytes(name).ok()?,
+                HeaderValue::from_bytes(value).ok()?,
+            ));
+        }
+
+        // Trailing bytes mean that the stored headers are corrupted
+        if cursor.is_empty() == false {
+            return None;
+        }
+
+        Some(decoded)
+    }
+
+    fn read_length(cursor: &mut &[u8]) -> Option<usize> {
+        if cursor.len() < ENVELOPE_LENGTH_SIZE {
+            return None;
+        }
+
+        let (length, rest) = cursor.split_at(ENVELOPE_LENGTH_SIZE);
+
+        *cursor = rest;
+
+        Some(u32::from_be_bytes(length.try_into().ok()?) as usize)
+    }
+
+    fn read_chunk<'a>(cursor: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let length = Self::read_length(cursor)?;
+
+        if cursor.len() < length {
+            return None;
+        }
+
+        let (chunk, rest) = cursor.split_at(length);
+
+        *cursor = rest;
+
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_and_decodes_envelope() {
+        let body = Bytes::from_static(&[0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0xff]);
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        headers.insert(
+            header::SERVER,
+            HeaderValue::from_bytes(b"Kestrel \xe9").unwrap(),
+        );
+
+        let envelope = CacheEnvelope::new(StatusCode::NOT_FOUND, &headers, body.clone());
+
+        let decoded = CacheEnvelope::decode(
+            &envelope.encode_status(),
+            &envelope.encode_headers(),
+            envelope.body.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(decoded.status, StatusCode::NOT_FOUND);
+        assert_eq!(decoded.headers, envelope.headers);
+        assert_eq!(decoded.body, body);
+    }
+
+    #[test]
+    fn it_fails_decoding_corrupted_envelope() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        let envelope = CacheEnvelope::new(StatusCode::OK, &headers, Bytes::new());
+
+        let headers = envelope.encode_headers();
+
+        assert!(
+            CacheEnvelope::decode(b"200", &headers[..headers.len() - 1], Bytes::new()).is_none()
+        );
+        assert!(
+            CacheEnvelope::decode(b"200", &[&headers[..], b"x"].concat(), Bytes::new()).is_none()
+        );
+        assert!(CacheEnvelope::decode(b"HTTP/1.1 200 OK", &headers, Bytes::new()).is_none());
+        assert!(CacheEnvelope::decode(b"200", &[], Bytes::new()).is_none());
+    }
+
+    #[test]
+    fn it_updates_envelope_headers() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("5"));
+        headers.insert(header::ETAG, HeaderValue::from_static("\"v1\""));
+        headers.append(header::LINK, HeaderValue::from_static("</a>; rel=preload"));
+        headers.append(header::LINK, HeaderValue::from_static("</b>; rel=preload"));
+
+        let mut envelope = CacheEnvelope::new(StatusCode::OK, &headers, Bytes::from("hello"));
+
+        let mut headers_update = HeaderMap::new();
+
+        headers_update.insert(header::CONTENT_LENGTH, HeaderValue::from_static("0"));
+        headers_update.insert(header::ETAG, HeaderValue::from_static("\"v2\""));
+        headers_update.insert(header::LINK, HeaderValue::from_static("</c>; rel=preload"));
+
+        envelope.update_headers(&headers_update);
+
+        assert_eq!(
+            envelope
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_bytes()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("content-type", &b"text/plain"[..]),
+                ("content-length", &b"5"[..]),
+                ("etag", &b"\"v2\""[..]),
+                ("link", &b"</c>; rel=preload"[..]),
+            ]
+        );
+        assert_eq!(envelope.body, Bytes::from("hello"));
+    }
+}
@@ -5,7 +5,11 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 pub mod check;
+pub mod encoding;
+pub mod envelope;
+pub mod policy;
 pub mod read;
 pub mod route;
 pub mod store;
+pub mod vary;
 pub mod write;
@@ -0,0 +1,261 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::time::SystemTime;
+
+use hyper::header::{self, HeaderMap, HeaderName};
+
+pub struct CachePolicy;
+
+#[derive(Default)]
+pub struct CachePolicyRequest {
+    pub no_cache: bool,
+    pub max_age: Option<u64>,
+    pub only_if_cached: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CachePolicyTTL {
+    Unspecified,
+    Uncacheable,
+    Value(usize),
+}
+
+impl CachePolicy {
+    pub fn from_response(headers: &HeaderMap) -> CachePolicyTTL {
+        // Notice: 'Surrogate-Control' targets proxy caches such as Bloom, \
+        //   thus it takes precedence over 'Cache-Control' (which targets \
+        //   all caches), which itself takes precedence over 'Expires'.
+        let surrogate_control =
+            Self::parse_directives(headers, HeaderName::from_static("surrogate-control"));
+
+        if Self::has_directive(&surrogate_control, "no-store") == true {
+            return CachePolicyTTL::Uncacheable;
+        }
+        if let Some(max_age) = Self::get_directive_seconds(&surrogate_control, "max-age") {
+            return Self::make_ttl(max_age);
+        }
+
+        let cache_control = Self::parse_directives(headers, header::CACHE_CONTROL);
+
+        // Responses that are private or that must always be revalidated \
+        //   should never be served from a shared cache
+        if Self::has_directive(&cache_control, "no-store") == true
+            || Self::has_directive(&cache_control, "private") == true
+            || Self::has_directive(&cache_control, "no-cache") == true
+        {
+            return CachePolicyTTL::Uncacheable;
+        }
+
+        if let Some(s_maxage) = Self::get_directive_seconds(&cache_control, "s-maxage") {
+            return Self::make_ttl(s_maxage);
+        }
+        if let Some(max_age) = Self::get_directive_seconds(&cache_control, "max-age") {
+            return Self::make_ttl(max_age);
+        }
+
+        // Compute TTL from 'Expires', relative to the response 'Date' (if any)
+        if let Some(expires) = headers.get(header::EXPIRES) {
+            let date = headers
+                .get(header::DATE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+                .unwrap_or_else(SystemTime::now);
+
+            // Notice: invalid 'Expires' values (eg. '0') mean that the \
+            //   response is already expired (RFC 9111, section 5.3)
+            return expires
+                .to_str()
+                .ok()
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+                .and_then(|expires| expires.duration_since(date).ok())
+                .map(|ttl| Self::make_ttl(ttl.as_secs()))
+                .unwrap_or(CachePolicyTTL::Uncacheable);
+        }
+
+        CachePolicyTTL::Unspecified
+    }
+
+    pub fn from_request(headers: &HeaderMap) -> CachePolicyRequest {
+        let cache_control = Self::parse_directives(headers, header::CACHE_CONTROL);
+
+        CachePolicyRequest {
+            no_cache: Self::has_directive(&cache_control, "no-cache"),
+            max_age: Self::get_directive_seconds(&cache_control, "max-age"),
+            only_if_cached: Self::has_directive(&cache_control, "only-if-cached"),
+        }
+    }
+
+    pub fn has_no_transform(headers: &HeaderMap) -> bool {
+        Self::has_directive(
+            &Self::parse_directives(headers, header::CACHE_CONTROL),
+            "no-transform",
+        )
+    }
+
+    fn parse_directives(headers: &HeaderMap, name: HeaderName) -> Vec<(String, Option<String>)> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|directive| {
+                let mut directive_parts = directive.splitn(2, '=');
+
+                let directive_name = directive_parts.next()?.trim().to_lowercase();
+
+                if directive_name.is_empty() == true {
+                    return None;
+                }
+
+                Some((
+                    directive_name,
+                    directive_parts
+                        .next()
+                        .map(|value| value.trim().trim_matches('"').to_string()),
+                ))
+            })
+            .collect()
+    }
+
+    fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+        directives
+            .iter()
+            .any(|(directive_name, _)| directive_name == name)
+    }
+
+    fn get_directive_seconds(directives: &[(String, Option<String>)], name: &str) -> Option<u64> {
+        directives
+            .iter()
+            .find(|(directive_name, _)| directive_name == name)
+            .and_then(|(_, value)| value.as_ref())
+            .and_then(|value| value.parse::<u64>().ok())
+    }
+
+    fn make_ttl(seconds: u64) -> CachePolicyTTL {
+        // A zero TTL means that the response is already stale upon receipt
+        if seconds > 0 {
+            CachePolicyTTL::Value(seconds as usize)
+        } else {
+            CachePolicyTTL::Uncacheable
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn make_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+
+        for (name, value) in headers {
+            header_map.append(*name, HeaderValue::from_static(value));
+        }
+
+        header_map
+    }
+
+    #[test]
+    fn it_ignores_responses_without_policy() {
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[("content-type", "text/plain")])),
+            CachePolicyTTL::Unspecified
+        );
+    }
+
+    #[test]
+    fn it_computes_ttl_from_cache_control() {
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[("cache-control", "public, max-age=60")])),
+            CachePolicyTTL::Value(60)
+        );
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[(
+                "cache-control",
+                "max-age=60, s-maxage=\"120\""
+            )])),
+            CachePolicyTTL::Value(120)
+        );
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[("cache-control", "max-age=0")])),
+            CachePolicyTTL::Uncacheable
+        );
+    }
+
+    #[test]
+    fn it_computes_ttl_from_surrogate_control() {
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[
+                ("surrogate-control", "max-age=300"),
+                ("cache-control", "private")
+            ])),
+            CachePolicyTTL::Value(300)
+        );
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[
+                ("surrogate-control", "no-store"),
+                ("cache-control", "max-age=60")
+            ])),
+            CachePolicyTTL::Uncacheable
+        );
+    }
+
+    #[test]
+    fn it_computes_ttl_from_expires() {
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[
+                ("date", "Thu, 01 Jan 2026 00:00:00 GMT"),
+                ("expires", "Thu, 01 Jan 2026 00:10:00 GMT")
+            ])),
+            CachePolicyTTL::Value(600)
+        );
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[
+                ("date", "Thu, 01 Jan 2026 00:00:00 GMT"),
+                ("expires", "Wed, 31 Dec 2025 00:00:00 GMT")
+            ])),
+            CachePolicyTTL::Uncacheable
+        );
+        assert_eq!(
+            CachePolicy::from_response(&make_headers(&[("expires", "0")])),
+            CachePolicyTTL::Uncacheable
+        );
+    }
+
+    #[test]
+    fn it_rejects_uncacheable_responses() {
+        for value in &["no-store", "private", "no-cache", "Private, max-age=60"] {
+            assert_eq!(
+                CachePolicy::from_response(&make_headers(&[("cache-control", value)])),
+                CachePolicyTTL::Uncacheable,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn it_parses_request_policy() {
+        let request_policy = CachePolicy::from_request(&make_headers(&[(
+            "cache-control",
+            "max-age=30, only-if-cached",
+        )]));
+
+        assert!(!request_policy.no_cache);
+        assert_eq!(request_policy.max_age, Some(30));
+        assert!(request_policy.only_if_cached);
+
+        let request_policy =
+            CachePolicy::from_request(&make_headers(&[("cache-control", "no-cache")]));
+
+        assert!(request_policy.no_cache);
+        assert_eq!(request_policy.max_age, None);
+        assert!(!request_policy.only_if_cached);
+    }
+}
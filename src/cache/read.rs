@@ -4,9 +4,15 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use hyper::Method;
+use hyper::{HeaderMap, Method};
 
 use super::check::CacheCheck;
+use super::encoding::CacheEncodingValue;
+use super::envelope::CacheEnvelope;
+use super::policy::CachePolicyRequest;
+use super::route::CacheRoute;
+use super::store::{CacheStoreError, CacheStoreLookup, CacheStoreMeta};
+use super::vary::{CacheVary, CacheVarySpec};
 
 use crate::APP_CACHE_STORE;
 use crate::APP_CONF;
@@ -17,22 +23,59 @@ pub enum CacheReadError {
     PassThrough,
     Empty,
     StoreFailure,
+    Expired(Box<CacheStoreMeta>),
 }
 
-type CacheReadResult = Result<(String, bool), CacheReadError>;
-type CacheReadOptionalResult = Result<Option<String>, CacheReadError>;
+type CacheReadResult = Result<CacheStoreMeta, CacheReadError>;
+type CacheReadOptionalResult = Result<Option<CacheEnvelope>, CacheReadError>;
 
 impl CacheRead {
     pub async fn acquire_meta(
         shard: u8,
         key: &str,
         method: &Method,
+        request_policy: &CachePolicyRequest,
+        headers: &HeaderMap,
     ) -> Result<CacheReadResult, ()> {
         if APP_CONF.cache.disable_read == false && CacheCheck::from_request(method) == true {
             debug!("key: {} cacheable, reading cache", &key);
 
-            match APP_CACHE_STORE.get_meta(shard, key.to_string()).await {
-                Ok(Some(result)) => Ok(Ok(result)),
+            // Client requested a refetch? Consider cache as non-existing, so \
+            //   that it gets refreshed
+            if request_policy.no_cache == true {
+                info!("ignored meta value from cache (no-cache requested)");
+
+                return Ok(Err(CacheReadError::Empty));
+            }
+
+            match Self::acquire_meta_scoped(shard, key, headers).await {
+                Ok(Some(result)) => {
+                    // Cache is older than what client accepts? Consider cache \
+                    //   as non-existing (entries with an unknown age are \
+                    //   considered too old)
+                    if let Some(max_age) = request_policy.max_age {
+                        if result.age().map(|age| age > max_age).unwrap_or(true) == true {
+                            info!(
+                                "ignored meta value from cache (older than {}s requested)",
+                                max_age
+                            );
+
+                            return Ok(Err(CacheReadError::Empty));
+                        }
+                    }
+
+                    // Cache is stale? (it can still be used as a fallback)
+                    if let Some(expired_for) = result.expired_for() {
+                        info!(
+                            "acquired expired meta value from cache (by {}s)",
+                            expired_for
+                        );
+
+                        Ok(Err(CacheReadError::Expired(Box::new(result))))
+                    } else {
+                        Ok(Ok(result))
+                    }
+                }
                 Ok(None) => {
                     info!("acquired empty meta value from cache");
 
@@ -51,16 +94,117 @@ impl CacheRead {
         }
     }
 
-    pub async fn acquire_body(key: &str, compressed: bool) -> Result<CacheReadOptionalResult, ()> {
-        match APP_CACHE_STORE.get_body(key.to_string(), compressed).await {
+    async fn acquire_meta_scoped(
+        shard: u8,
+        key: &str,
+        headers: &HeaderMap,
+    ) -> Result<Option<CacheStoreMeta>, CacheStoreError> {
+        // Acquire public namespace of route (if requester is not anonymous, \
+        //   as anonymous requests already use the public namespace)
+        let public_key =
+            CacheRoute::gen_key_cache_public(shard, key).map(|(public_key, _)| public_key);
+        let scope_key = public_key
+            .as_ref()
+            .and_then(|_| CacheRoute::gen_key_scope(shard, key));
+
+        let (lookup, public_route) = APP_CACHE_STORE
+            .get_meta_scoped(shard, key.to_string(), scope_key)
+            .await?;
+
+        // Route is marked as holding a public response? Look it up, as it \
+        //   can be served to any requester
+        let public_meta = match public_key {
+            Some(ref public_key) if public_route == true => {
+                debug!("key: {} has public scope, reading: {}", key, public_key);
+
+                match APP_CACHE_STORE
+                    .get_meta(shard, public_key.to_owned())
+                    .await?
+                {
+                    Some(public_lookup) => {
+                        Self::acquire_meta_variant(shard, public_key, public_lookup, headers)
+                            .await?
+                    }
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+
+        let private_meta = match lookup {
+            Some(lookup) => Self::acquire_meta_variant(shard, key, lookup, headers).await?,
+            None => None,
+        };
+
+        Ok(Self::pick_meta_scoped(public_meta, private_meta))
+    }
+
+    fn pick_meta_scoped(
+        public_meta: Option<CacheStoreMeta>,
+        private_meta: Option<CacheStoreMeta>,
+    ) -> Option<CacheStoreMeta> {
+        // Notice: only entries marked as public count, since the public \
+        //   namespace is shared with anonymous requests.
+        match public_meta.filter(|meta| meta.public == true) {
+            Some(public_meta) if public_meta.expired_for().is_none() => Some(public_meta),
+
+            // Public response is stale? Prefer a private response (if any), \
+            //   as the API may not mark the route as public anymore
+            Some(public_meta) => private_meta.or(Some(public_meta)),
+            None => private_meta,
+        }
+    }
+
+    async fn acquire_meta_variant(
+        shard: u8,
+        key: &str,
+        lookup: CacheStoreLookup,
+        headers: &HeaderMap,
+    ) -> Result<Option<CacheStoreMeta>, CacheStoreError> {
+        match lookup {
+            CacheStoreLookup::Meta(meta) => Ok(Some(meta)),
+            CacheStoreLookup::Vary(vary) => {
+                // Route varies on request headers? Acquire the variant that \
+                //   matches request headers
+                if let CacheVarySpec::Headers(names) = CacheVary::from_stored(&vary) {
+                    let variant_key =
+                        CacheRoute::gen_key_vary(key, &CacheVary::hash(&names, headers));
+
+                    debug!("key: {} varies on: {}, reading variant", key, vary);
+
+                    // Notice: a variant cannot vary itself, thus a nested \
+                    //   vary spec is considered as non-existing.
+                    match APP_CACHE_STORE.get_meta(shard, variant_key).await? {
+                        Some(CacheStoreLookup::Meta(meta)) => Ok(Some(meta)),
+                        _ => Ok(None),
+                    }
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    pub async fn acquire_envelope(
+        key: &str,
+        compressed: bool,
+        encoding: Option<CacheEncodingValue>,
+    ) -> Result<CacheReadOptionalResult, ()> {
+        match APP_CACHE_STORE
+            .get_envelope(key.to_string(), compressed, encoding)
+            .await
+        {
             Ok(Some(result)) => Ok(Ok(Some(result))),
             Ok(None) => {
-                info!("acquired empty body value from cache");
+                info!("acquired empty envelope value from cache");
 
                 Ok(Err(CacheReadError::Empty))
             }
             Err(err) => {
-                error!("could not acquire body value from cache because: {:?}", err);
+                error!(
+                    "could not acquire envelope value from cache because: {:?}",
+                    err
+                );
 
                 Ok(Err(CacheReadError::StoreFailure))
             }
@@ -72,21 +216,86 @@ impl CacheRead {
 mod tests {
     use super::*;
 
+    use crate::cache::store::CacheStoreValidators;
+
+    fn make_meta(fingerprint: &str, expires: Option<u64>, public: bool) -> CacheStoreMeta {
+        CacheStoreMeta {
+            key: "bloom:0:c:dc56d17a:e6a8b05d".to_string(),
+            fingerprint: fingerprint.to_string(),
+            compressed: false,
+            expires,
+            created: Some(400),
+            modified: Some(400),
+            revalidate: 0,
+            validators: CacheStoreValidators::default(),
+            tags: Vec::new(),
+            public,
+        }
+    }
+
+    fn pick_fingerprint(
+        public_meta: Option<CacheStoreMeta>,
+        private_meta: Option<CacheStoreMeta>,
+    ) -> Option<String> {
+        CacheRead::pick_meta_scoped(public_meta, private_meta).map(|meta| meta.fingerprint)
+    }
+
+    #[test]
+    fn it_picks_public_meta() {
+        assert_eq!(
+            pick_fingerprint(
+                Some(make_meta("public", None, true)),
+                Some(make_meta("private", None, false))
+            ),
+            Some("public".to_string())
+        );
+        assert_eq!(
+            pick_fingerprint(
+                Some(make_meta("public", Some(1), true)),
+                Some(make_meta("private", None, false))
+            ),
+            Some("private".to_string())
+        );
+        assert_eq!(
+            pick_fingerprint(Some(make_meta("public", Some(1), true)), None),
+            Some("public".to_string())
+        );
+    }
+
+    #[test]
+    fn it_never_picks_anonymous_meta_as_public() {
+        assert_eq!(
+            pick_fingerprint(Some(make_meta("anonymous", None, false)), None),
+            None
+        );
+        assert_eq!(
+            pick_fingerprint(
+                Some(make_meta("anonymous", None, false)),
+                Some(make_meta("private", None, false))
+            ),
+            Some("private".to_string())
+        );
+    }
+
     #[tokio::test]
     #[should_panic]
     async fn it_fails_acquiring_cache_meta() {
-        assert!(
-            CacheRead::acquire_meta(0, "bloom:0:c:90d52bc6:f773d6f1", &Method::GET)
-                .await
-                .is_err()
-        );
+        assert!(CacheRead::acquire_meta(
+            0,
+            "bloom:0:c:90d52bc6:f773d6f1",
+            &Method::GET,
+            &CachePolicyRequest::default(),
+            &HeaderMap::new()
+        )
+        .await
+        .is_err());
     }
 
     #[tokio::test]
     #[should_panic]
-    async fn it_fails_acquiring_cache_body() {
+    async fn it_fails_acquiring_cache_envelope() {
         assert!(
-            CacheRead::acquire_body("bloom:0:c:90d52bc6:f773d6f1", false)
+            CacheRead::acquire_envelope("bloom:0:c:90d52bc6:f773d6f1", false, None)
                 .await
                 .is_err()
         );
@@ -4,15 +4,34 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
 use farmhash;
-use hyper::{Method, Version};
+use hyper::header::{self, HeaderMap};
+use hyper::{Method, Uri, Version};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use xxhash_rust::xxh3;
 
-pub struct CacheRoute;
+use super::vary::CacheVary;
+use crate::config::config::{ConfigCacheHasher, ConfigProxyShardCacheKey};
+use crate::APP_CONF;
 
-pub const ROUTE_HASH_SIZE: usize = 8;
+pub struct CacheRoute;
 
 pub static ROUTE_PREFIX: &'static str = "bloom";
 
+pub static ROUTE_AUTH_PUBLIC: &'static str = "";
+
+static ROUTE_HASHER: OnceLock<ConfigCacheHasher> = OnceLock::new();
+
+// Encode all characters but RFC 3986 unreserved characters
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 impl CacheRoute {
     pub fn gen_key_cache_from_hash(
         shard: u8,
@@ -24,6 +43,36 @@ impl CacheRoute {
         (format!("{}:{}:c:{}", ROUTE_PREFIX, shard, &mask), mask)
     }
 
+    pub fn gen_key_cache_public(shard: u8, key: &str) -> Option<(String, String)> {
+        // Notice: public responses are stored in the auth-less namespace of \
+        //   their route, which is the namespace of anonymous requests.
+        let (auth_hash, route_mask) = key
+            .strip_prefix(&format!("{}:{}:c:", ROUTE_PREFIX, shard))?
+            .split_once(':')?;
+
+        let public_hash = Self::hash(ROUTE_AUTH_PUBLIC);
+
+        if auth_hash == public_hash {
+            None
+        } else {
+            Some(Self::gen_key_cache_from_hash(
+                shard,
+                &public_hash,
+                route_mask,
+            ))
+        }
+    }
+
+    pub fn gen_key_scope(shard: u8, key: &str) -> Option<String> {
+        // Notice: the scope marker of a route is shared by all requesters, \
+        //   so that all of them know whether the route has a public entry.
+        let (_, route_mask) = key
+            .strip_prefix(&format!("{}:{}:c:", ROUTE_PREFIX, shard))?
+            .split_once(':')?;
+
+        Some(format!("{}:{}:s:{}", ROUTE_PREFIX, shard, route_mask))
+    }
+
     pub fn gen_key_auth_from_hash(shard: u8, auth_hash: &str) -> (String, String) {
         let mask = format!("a:{}", auth_hash);
 
@@ -45,6 +94,22 @@ impl CacheRoute {
         query: Option<&str>,
         origin: Option<&str>,
     ) -> (String, String) {
+        // Notice: 'HEAD' requests are keyed as 'GET' requests, so that they \
+        //   get served from cached 'GET' responses.
+        let method = if method == Method::HEAD {
+            &Method::GET
+        } else {
+            method
+        };
+
+        // Notice: HTTP/2 and HTTP/3 requests are keyed as HTTP/1.1 requests, \
+        //   as they carry the same semantics. This keeps keys stable across \
+        //   protocols, while HTTP/1.0 requests still get their own keys.
+        let version = match version {
+            Version::HTTP_2 | Version::HTTP_3 => Version::HTTP_11,
+            version => version,
+        };
+
         let bucket_raw = format!(
             "[{:?}|{}|{}|{}|{}]",
             version,
@@ -61,10 +126,225 @@ impl CacheRoute {
         Self::gen_key_cache_from_hash(shard, auth_hash, &route_hash)
     }
 
+    pub fn gen_key_cache_from_request(
+        shard: u8,
+        auth_hash: &str,
+        version: Version,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> (String, String) {
+        match APP_CONF.proxy.find_shard(shard) {
+            Some(shard_config) => Self::gen_key_cache_with_rules(
+                shard,
+                auth_hash,
+                version,
+                method,
+                uri,
+                headers,
+                &shard_config.cache_key,
+            ),
+            None => Self::gen_key_cache(
+                shard,
+                auth_hash,
+                version,
+                method,
+                uri.path(),
+                uri.query(),
+                Self::get_origin(headers),
+            ),
+        }
+    }
+
+    pub fn gen_key_vary(key: &str, vary_hash: &str) -> String {
+        // Notice: this applies to both route keys and key masks, so that \
+        //   variants get purged from the tags they have been stored with.
+        format!("{}:{}", key, vary_hash)
+    }
+
+    pub fn normalize_query<'a>(
+        query: Option<&'a str>,
+        rules: &ConfigProxyShardCacheKey,
+    ) -> Option<Cow<'a, str>> {
+        let query = query?;
+
+        // Use query as-is if there are no rules to apply to it
+        if rules.include_query.is_empty() == true
+            && rules.exclude_query.is_empty() == true
+            && rules.exclude_query_patterns.is_empty() == true
+            && rules.sort_query == false
+            && rules.normalize_query == false
+        {
+            return Some(Cow::Borrowed(query));
+        }
+
+        let mut params = query
+            .split('&')
+            .filter(|param| param.is_empty() == false)
+            .map(|param| {
+                if rules.normalize_query == true {
+                    Cow::Owned(Self::normalize_query_param(param))
+                } else {
+                    Cow::Borrowed(param)
+                }
+            })
+            .filter(|param| {
+                let name = Self::get_query_param_name(param);
+
+                (rules.include_query.is_empty() == true
+                    || rules.include_query.iter().any(|include| include == name))
+                    && rules.exclude_query.iter().any(|exclude| exclude == name) == false
+                    && rules
+                        .exclude_query_patterns
+                        .iter()
+                        .any(|pattern| pattern.is_match(name))
+                        == false
+            })
+            .collect::<Vec<Cow<str>>>();
+
+        // Notice: sort on names only, as the order of repeated parameters \
+        //   may be meaningful to the API.
+        if rules.sort_query == true {
+            params.sort_by(|param, other_param| {
+                Self::get_query_param_name(param).cmp(Self::get_query_param_name(other_param))
+            });
+        }
+
+        Some(Cow::Owned(params.join("&")))
+    }
+
+    pub fn bind_hasher(hasher: ConfigCacheHasher) {
+        if ROUTE_HASHER.set(hasher).is_err() {
+            panic!("route hasher is already bound");
+        }
+
+        info!("bound route hasher to: {}", hasher.to_str());
+    }
+
+    pub fn hasher() -> ConfigCacheHasher {
+        // Notice: fallback to the legacy hasher if none was bound (eg. in \
+        //   tests), as it is the default one.
+        ROUTE_HASHER
+            .get()
+            .copied()
+            .unwrap_or(ConfigCacheHasher::FarmHash32)
+    }
+
     pub fn hash(value: &str) -> String {
         debug!("hashing value: {}", value);
 
-        format!("{:x}", farmhash::fingerprint32(value.as_bytes()))
+        Self::hash_with(Self::hasher(), value)
+    }
+
+    pub fn hash_size() -> usize {
+        Self::hash_size_with(Self::hasher())
+    }
+
+    fn gen_key_cache_with_rules(
+        shard: u8,
+        auth_hash: &str,
+        version: Version,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        rules: &ConfigProxyShardCacheKey,
+    ) -> (String, String) {
+        // Notice: when the HTTP version is excluded, all versions are keyed \
+        //   as HTTP/1.1, so that keys for the most common version remain the \
+        //   same as when it is included.
+        let version = if rules.include_version == true {
+            version
+        } else {
+            Version::HTTP_11
+        };
+
+        let origin = if rules.include_origin == true {
+            Self::get_origin(headers)
+        } else {
+            None
+        };
+
+        let query = Self::normalize_query(uri.query(), rules);
+
+        let (key, key_mask) = Self::gen_key_cache(
+            shard,
+            auth_hash,
+            version,
+            method,
+            uri.path(),
+            query.as_deref(),
+            origin,
+        );
+
+        // Included request headers are keyed the same way as response \
+        //   variants, though their list is known before the request is sent
+        if rules.include_headers.is_empty() == false {
+            let headers_hash = CacheVary::hash(&rules.include_headers, headers);
+
+            (
+                Self::gen_key_vary(&key, &headers_hash),
+                Self::gen_key_vary(&key_mask, &headers_hash),
+            )
+        } else {
+            (key, key_mask)
+        }
+    }
+
+    fn hash_with(hasher: ConfigCacheHasher, value: &str) -> String {
+        // Notice: the legacy 32 bits hash is not zero-padded, as control \
+        //   clients may compute it this way.
+        match hasher {
+            ConfigCacheHasher::FarmHash32 => {
+                format!("{:x}", farmhash::fingerprint32(value.as_bytes()))
+            }
+            ConfigCacheHasher::FarmHash64 => {
+                format!("{:016x}", farmhash::fingerprint64(value.as_bytes()))
+            }
+            ConfigCacheHasher::Xxh3_64 => format!("{:016x}", xxh3::xxh3_64(value.as_bytes())),
+            ConfigCacheHasher::Xxh3_128 => format!("{:032x}", xxh3::xxh3_128(value.as_bytes())),
+        }
+    }
+
+    fn hash_size_with(hasher: ConfigCacheHasher) -> usize {
+        match hasher {
+            ConfigCacheHasher::FarmHash32 => 8,
+            ConfigCacheHasher::FarmHash64 | ConfigCacheHasher::Xxh3_64 => 16,
+            ConfigCacheHasher::Xxh3_128 => 32,
+        }
+    }
+
+    fn get_origin(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok())
+    }
+
+    fn get_query_param_name(param: &str) -> &str {
+        param.split('=').next().unwrap_or("")
+    }
+
+    fn normalize_query_param(param: &str) -> String {
+        // Decode then re-encode parameter name and value, so that a same \
+        //   parameter always gets encoded the same way
+        // Notice: '+' is left as-is, as it stands for a space in queries, \
+        //   while an encoded '+' (ie. '%2B') stands for an actual '+'.
+        param
+            .splitn(2, '=')
+            .map(|component| {
+                component
+                    .split('+')
+                    .map(|part| {
+                        percent_encode(
+                            &percent_decode_str(part).collect::<Vec<u8>>(),
+                            QUERY_ENCODE_SET,
+                        )
+                        .to_string()
+                    })
+                    .collect::<Vec<String>>()
+                    .join("+")
+            })
+            .collect::<Vec<String>>()
+            .join("=")
     }
 }
 
@@ -72,6 +352,20 @@ impl CacheRoute {
 mod tests {
     use super::*;
 
+    fn make_rules() -> ConfigProxyShardCacheKey {
+        ConfigProxyShardCacheKey {
+            include_version: true,
+            include_origin: true,
+            include_headers: Vec::new(),
+            include_query: Vec::new(),
+            exclude_query: Vec::new(),
+            exclude_query_patterns: Vec::new(),
+            sort_query: false,
+            normalize_query: false,
+            forward_query: false,
+        }
+    }
+
     #[test]
     fn it_generates_valid_ns() {
         assert_eq!(
@@ -133,15 +427,271 @@ mod tests {
                 Some("https://valeriansaliou.name"),
             ),
             (
-                "bloom:80:c:d73f0f31:29ee2e71".to_string(),
-                "d73f0f31:29ee2e71".to_string(),
+                "bloom:80:c:d73f0f31:ef6ec074".to_string(),
+                "d73f0f31:ef6ec074".to_string(),
             ),
             "[shard=80][auth=yes] h2 HEAD /feed"
         );
         assert_eq!(
-            ROUTE_HASH_SIZE,
+            CacheRoute::gen_key_cache(
+                80,
+                "d73f0f31",
+                Version::HTTP_2,
+                &Method::GET,
+                "/user",
+                Some("u=1"),
+                Some("https://valeriansaliou.name"),
+            ),
+            (
+                "bloom:80:c:d73f0f31:ef6ec074".to_string(),
+                "d73f0f31:ef6ec074".to_string(),
+            ),
+            "[shard=80][auth=yes] h2 GET /feed"
+        );
+        assert_eq!(
+            CacheRoute::gen_key_cache(
+                80,
+                "d73f0f31",
+                Version::HTTP_11,
+                &Method::GET,
+                "/user",
+                Some("u=1"),
+                Some("https://valeriansaliou.name"),
+            ),
+            (
+                "bloom:80:c:d73f0f31:ef6ec074".to_string(),
+                "d73f0f31:ef6ec074".to_string(),
+            ),
+            "[shard=80][auth=yes] HTTP/1.1 GET /feed"
+        );
+        assert_eq!(
+            CacheRoute::gen_key_vary("bloom:0:c:dc56d17a:e6a8b05d", "5b6b0d0c"),
+            "bloom:0:c:dc56d17a:e6a8b05d:5b6b0d0c".to_string(),
+            "[shard=0][auth=no] HTTP/1.1 GET / (variant)"
+        );
+        assert_eq!(
+            CacheRoute::hash_size(),
             CacheRoute::hash("7gCq81kzO5").len(),
             "Route size should be 8 (dynamic)"
         );
     }
+
+    #[test]
+    fn it_generates_public_ns() {
+        let public_hash = CacheRoute::hash("");
+
+        assert_eq!(
+            CacheRoute::gen_key_cache_public(0, "bloom:0:c:90d52bc6:e6a8b05d:5e1ac6f3"),
+            Some((
+                format!("bloom:0:c:{}:e6a8b05d:5e1ac6f3", public_hash),
+                format!("{}:e6a8b05d:5e1ac6f3", public_hash)
+            ))
+        );
+        assert_eq!(
+            CacheRoute::gen_key_cache_public(0, &format!("bloom:0:c:{}:e6a8b05d", public_hash)),
+            None
+        );
+        assert_eq!(
+            CacheRoute::gen_key_cache_public(1, "bloom:0:c:90d52bc6:e6a8b05d"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_generates_scope_key() {
+        let public_hash = CacheRoute::hash("");
+
+        assert_eq!(
+            CacheRoute::gen_key_scope(0, "bloom:0:c:90d52bc6:e6a8b05d:5e1ac6f3"),
+            Some("bloom:0:s:e6a8b05d:5e1ac6f3".to_string())
+        );
+        assert_eq!(
+            CacheRoute::gen_key_scope(0, &format!("bloom:0:c:{}:e6a8b05d", public_hash)),
+            Some("bloom:0:s:e6a8b05d".to_string())
+        );
+        assert_eq!(
+            CacheRoute::gen_key_scope(1, "bloom:0:c:90d52bc6:e6a8b05d"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_generates_same_ns_with_default_rules() {
+        let uri: Uri = "/user?b=1&a=2".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            header::ORIGIN,
+            "https://valeriansaliou.name".parse().unwrap(),
+        );
+
+        assert_eq!(
+            CacheRoute::gen_key_cache_with_rules(
+                0,
+                "dc56d17a",
+                Version::HTTP_10,
+                &Method::GET,
+                &uri,
+                &headers,
+                &make_rules(),
+            ),
+            CacheRoute::gen_key_cache(
+                0,
+                "dc56d17a",
+                Version::HTTP_10,
+                &Method::GET,
+                "/user",
+                Some("b=1&a=2"),
+                Some("https://valeriansaliou.name"),
+            )
+        );
+    }
+
+    #[test]
+    fn it_generates_ns_with_rules() {
+        let mut rules = make_rules();
+
+        rules.include_version = false;
+        rules.include_origin = false;
+        rules.sort_query = true;
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert(
+            header::ORIGIN,
+            "https://valeriansaliou.name".parse().unwrap(),
+        );
+
+        let ns = CacheRoute::gen_key_cache_with_rules(
+            0,
+            "dc56d17a",
+            Version::HTTP_10,
+            &Method::GET,
+            &"/user?b=1&a=2".parse().unwrap(),
+            &headers,
+            &rules,
+        );
+
+        assert_eq!(
+            ns,
+            CacheRoute::gen_key_cache_with_rules(
+                0,
+                "dc56d17a",
+                Version::HTTP_11,
+                &Method::GET,
+                &"/user?a=2&b=1".parse().unwrap(),
+                &HeaderMap::new(),
+                &rules,
+            )
+        );
+
+        rules.include_headers = vec!["accept-language".to_string()];
+
+        headers.insert(header::ACCEPT_LANGUAGE, "fr".parse().unwrap());
+
+        let ns_headers = CacheRoute::gen_key_cache_with_rules(
+            0,
+            "dc56d17a",
+            Version::HTTP_10,
+            &Method::GET,
+            &"/user?b=1&a=2".parse().unwrap(),
+            &headers,
+            &rules,
+        );
+
+        assert!(ns_headers.0.starts_with(&format!("{}:", ns.0)));
+        assert!(ns_headers.1.starts_with(&format!("{}:", ns.1)));
+    }
+
+    #[test]
+    fn it_processes_query_with_rules() {
+        let mut rules = make_rules();
+
+        assert_eq!(
+            CacheRoute::normalize_query(Some("b=1&a=2"), &rules).as_deref(),
+            Some("b=1&a=2")
+        );
+        assert_eq!(CacheRoute::normalize_query(None, &rules), None);
+
+        rules.sort_query = true;
+
+        assert_eq!(
+            CacheRoute::normalize_query(Some("b=1&a=2&b=0&&c"), &rules).as_deref(),
+            Some("a=2&b=1&b=0&c")
+        );
+
+        rules.exclude_query = vec!["b".to_string()];
+
+        assert_eq!(
+            CacheRoute::normalize_query(Some("b=1&a=2&c"), &rules).as_deref(),
+            Some("a=2&c")
+        );
+
+        rules.include_query = vec!["a".to_string(), "b".to_string()];
+
+        assert_eq!(
+            CacheRoute::normalize_query(Some("b=1&a=2&c"), &rules).as_deref(),
+            Some("a=2")
+        );
+    }
+    #[test]
+    fn it_normalizes_query() {
+        let mut rules = make_rules();
+
+        rules.normalize_query = true;
+
+        assert_eq!(
+            CacheRoute::normalize_query(Some("q=caf%c3%a9+au%20lait&t=%7e1&p=1%2B1&s=é"), &rules)
+                .as_deref(),
+            Some("q=caf%C3%A9+au%20lait&t=~1&p=1%2B1&s=%C3%A9")
+        );
+        assert_eq!(
+            CacheRoute::normalize_query(Some("a&b=&%61=1"), &rules).as_deref(),
+            Some("a&b=&a=1")
+        );
+
+        rules.sort_query = true;
+        rules.exclude_query = vec!["fbclid".to_string()];
+        rules.exclude_query_patterns = vec![regex::Regex::new("^utm_").unwrap()];
+
+        assert_eq!(
+            CacheRoute::normalize_query(
+                Some("utm_source=mail&page=2&fbclid=x1&utm%5Fmedium=web&id=1"),
+                &rules
+            )
+            .as_deref(),
+            Some("id=1&page=2")
+        );
+    }
+    #[test]
+    fn it_hashes_with_all_hashers() {
+        for hasher in &[
+            ConfigCacheHasher::FarmHash32,
+            ConfigCacheHasher::FarmHash64,
+            ConfigCacheHasher::Xxh3_64,
+            ConfigCacheHasher::Xxh3_128,
+        ] {
+            let hash = CacheRoute::hash_with(*hasher, "7gCq81kzO5");
+
+            assert_eq!(
+                hash.len(),
+                CacheRoute::hash_size_with(*hasher),
+                "{:?}",
+                hasher
+            );
+            assert_eq!(
+                hash,
+                CacheRoute::hash_with(*hasher, "7gCq81kzO5"),
+                "{:?}",
+                hasher
+            );
+            assert_ne!(
+                hash,
+                CacheRoute::hash_with(*hasher, "7gCq81kzO6"),
+                "{:?}",
+                hasher
+            );
+        }
+    }
 }
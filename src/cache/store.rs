@@ -5,23 +5,42 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use std::cmp;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
+use hyper::header::{self, HeaderValue};
 use redis::aio::ConnectionManager;
 use redis::{self, AsyncCommands, Client, Value};
 use tokio::sync::OnceCell;
 
+use super::encoding::{CacheEncoding, CacheEncodingValue};
+use super::envelope::{CacheEnvelope, ENVELOPE_VERSION};
 use super::route::ROUTE_PREFIX;
 use crate::APP_CONF;
 
+static KEY_VERSION: &'static str = "v";
+static KEY_STATUS: &'static str = "s";
+static KEY_HEADERS: &'static str = "h";
 static KEY_BODY: &'static str = "b";
+static KEY_BODY_BROTLI: &'static str = "bb";
+static KEY_BODY_ZSTD: &'static str = "bz";
+static KEY_BODY_GZIP: &'static str = "bg";
 static KEY_FINGERPRINT: &'static str = "f";
 static KEY_COMPRESSED: &'static str = "c";
 static KEY_TAGS: &'static str = "t";
+static KEY_EXPIRES: &'static str = "e";
+static KEY_REVALIDATE: &'static str = "r";
+static KEY_ETAG: &'static str = "g";
+static KEY_LAST_MODIFIED: &'static str = "m";
+static KEY_CREATED: &'static str = "d";
+static KEY_MODIFIED: &'static str = "l";
+static KEY_VARY: &'static str = "y";
+static KEY_PUBLIC: &'static str = "p";
 static KEY_TAGS_SEPARATOR: &'static str = ",";
 
 static VALUE_COMPRESSED_YES: &'static [u8] = "1".as_bytes();
 static VALUE_COMPRESSED_NO: &'static [u8] = "0".as_bytes();
+static VALUE_PUBLIC_YES: &'static [u8] = "1".as_bytes();
 
 pub struct CacheStoreBuilder;
 
@@ -36,6 +55,37 @@ pub struct CacheStoreConnections {
     scripts: OnceCell<ConnectionManager>,
 }
 
+#[derive(Clone, Copy)]
+pub struct CacheStoreExpiry {
+    pub ttl: usize,
+    pub grace: usize,
+    pub revalidate: usize,
+}
+
+pub struct CacheStoreMeta {
+    pub key: String,
+    pub fingerprint: String,
+    pub compressed: bool,
+    pub expires: Option<u64>,
+    pub created: Option<u64>,
+    pub modified: Option<u64>,
+    pub revalidate: u64,
+    pub validators: CacheStoreValidators,
+    pub tags: Vec<String>,
+    pub public: bool,
+}
+
+pub enum CacheStoreLookup {
+    Meta(CacheStoreMeta),
+    Vary(String),
+}
+
+#[derive(Clone, Default)]
+pub struct CacheStoreValidators {
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+}
+
 #[derive(Debug)]
 pub enum CacheStoreError {
     Disconnected,
@@ -93,87 +143,203 @@ impl CacheStore {
         &self,
         shard: u8,
         key: String,
-    ) -> Result<Option<(String, bool)>, CacheStoreError> {
+    ) -> Result<Option<CacheStoreLookup>, CacheStoreError> {
+        self.get_meta_scoped(shard, key, None)
+            .await
+            .map(|(lookup, _)| lookup)
+    }
+
+    pub async fn get_meta_scoped(
+        &self,
+        shard: u8,
+        key: String,
+        scope_key: Option<String>,
+    ) -> Result<(Option<CacheStoreLookup>, bool), CacheStoreError> {
         let mut connection = self.get_main_conn_unreliable().await?;
 
-        match connection
-            .hmget::<_, _, Vec<Value>>(&key, &[KEY_FINGERPRINT, KEY_COMPRESSED, KEY_TAGS])
-            .await
-        {
-            Ok(values) => {
-                let mut values_iter = values.into_iter();
+        let mut pipeline = redis::pipe();
+
+        pipeline.hmget(
+            &key,
+            &[
+                KEY_FINGERPRINT,
+                KEY_COMPRESSED,
+                KEY_TAGS,
+                KEY_VERSION,
+                KEY_EXPIRES,
+                KEY_REVALIDATE,
+                KEY_ETAG,
+                KEY_LAST_MODIFIED,
+                KEY_CREATED,
+                KEY_MODIFIED,
+                KEY_PUBLIC,
+                KEY_VARY,
+            ],
+        );
+
+        // Check whether route is marked as public in the same round-trip \
+        //   (if asked for), so that routes that are never public do not get \
+        //   looked up twice
+        if let Some(ref scope_key) = scope_key {
+            pipeline.exists(scope_key);
+        }
+
+        match pipeline.query_async::<Vec<Value>>(&mut connection).await {
+            Ok(results) => {
+                let mut results_iter = results.into_iter();
+
+                let mut values_iter = match results_iter.next() {
+                    Some(Value::Array(values)) => values.into_iter(),
+                    _ => return Err(CacheStoreError::Invalid),
+                };
+
+                let public_route = results_iter.next() == Some(Value::Int(1));
+
+                match values_iter.next() {
+                    Some(Value::BulkString(fingerprint_bytes)) => {
+                        let (
+                            compressed_bytes,
+                            tags_bytes,
+                            version_bytes,
+                            expires_bytes,
+                            revalidate_bytes,
+                            etag_bytes,
+                            last_modified_bytes,
+                            created_bytes,
+                            modified_bytes,
+                            public_bytes,
+                        ) = (
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                            Self::parse_value_bytes(values_iter.next()),
+                        );
+
+                        // Stored with another envelope version? Consider cache as \
+                        //   non-existing (eg. legacy entries written by an older \
+                        //   Bloom version, which will get overwritten upon refresh)
+                        if version_bytes.as_deref() != Some(ENVELOPE_VERSION) {
+                            info!("got cache with incompatible envelope version");
+
+                            return Ok((None, public_route));
+                        }
 
-                match (values_iter.next(), values_iter.next(), values_iter.next()) {
-                    (
-                        Some(Value::BulkString(fingerprint_bytes)),
-                        Some(compressed_bytes),
-                        Some(tags_bytes),
-                    ) => {
                         // Parse compressed flag value (if any)
-                        let compressed =
-                            if let Value::BulkString(compressed_value) = compressed_bytes {
-                                compressed_value == VALUE_COMPRESSED_YES
-                            } else {
-                                false
-                            };
-
-                        // Parse tags and bump their last access time
-                        if let Value::BulkString(tags_bytes_data) = tags_bytes {
-                            if let Ok(tags_data) = String::from_utf8(tags_bytes_data) {
-                                if tags_data.is_empty() == false {
-                                    let tags = tags_data
-                                        .split(KEY_TAGS_SEPARATOR)
-                                        .map(|tag| format!("{}:{}:{}", ROUTE_PREFIX, shard, tag))
-                                        .collect::<Vec<String>>();
-
-                                    // Proceed a soft bump of last access time of \
-                                    //   associated tag keys. This prevents a \
-                                    //   frequently accessed cache namespace to \
-                                    //   become 'orphan' (ie. one or more tag keys \
-                                    //   are LRU-expired), and thus cache namespace \
-                                    //   not to be properly removed on purge of an \
-                                    //   associated tag.
-                                    // Also, count bumped keys. It may happen that \
-                                    //   some tag keys are incorrectly removed by \
-                                    //   Redis LRU system, as it is probabilistic \
-                                    //   and thus might sample some keys incorrectly.
-                                    // The conditions explained above only happens on \
-                                    //   Redis instances with used memory going over \
-                                    //   the threshold of the max memory policy.
-                                    let tags_count = tags.len();
-
-                                    match redis::cmd("TOUCH")
-                                        .arg(tags)
-                                        .query_async::<usize>(&mut connection)
-                                        .await
-                                    {
-                                        Ok(bump_count) => {
-                                            // Partial bump count? Consider cache as non-existing
-                                            if bump_count < tags_count {
-                                                info!(
-                                                    "got only partial tag count: {}/{}",
-                                                    bump_count, tags_count
-                                                );
-
-                                                return Ok(None);
-                                            }
-                                        }
-                                        Err(err) => {
-                                            error!("error bumping access time of tags: {}", err);
-                                        }
+                        let compressed = compressed_bytes.as_deref() == Some(VALUE_COMPRESSED_YES);
+
+                        // Parse public scope flag value (if any)
+                        let public = public_bytes.as_deref() == Some(VALUE_PUBLIC_YES);
+
+                        // Parse expiration time value (if any)
+                        // Notice: entries that have no expiration time are \
+                        //   considered fresh until Redis expires them.
+                        let expires = Self::parse_value_number(expires_bytes);
+
+                        // Parse creation time value (if any)
+                        let created = Self::parse_value_number(created_bytes);
+
+                        // Parse modification time value (if any)
+                        let modified = Self::parse_value_number(modified_bytes);
+
+                        // Parse revalidate window value (if any)
+                        let revalidate = Self::parse_value_number(revalidate_bytes).unwrap_or(0);
+
+                        // Parse validators values (if any)
+                        let validators = CacheStoreValidators {
+                            etag: Self::parse_value_header(etag_bytes),
+                            last_modified: Self::parse_value_header(last_modified_bytes),
+                        };
+
+                        // Parse tags (if any)
+                        let tags = tags_bytes
+                            .and_then(|tags_bytes_data| String::from_utf8(tags_bytes_data).ok())
+                            .filter(|tags_data| tags_data.is_empty() == false)
+                            .map(|tags_data| {
+                                tags_data
+                                    .split(KEY_TAGS_SEPARATOR)
+                                    .map(|tag| format!("{}:{}:{}", ROUTE_PREFIX, shard, tag))
+                                    .collect::<Vec<String>>()
+                            })
+                            .unwrap_or_default();
+
+                        // Bump last access time of tags
+                        if tags.is_empty() == false {
+                            // Proceed a soft bump of last access time of \
+                            //   associated tag keys. This prevents a \
+                            //   frequently accessed cache namespace to \
+                            //   become 'orphan' (ie. one or more tag keys \
+                            //   are LRU-expired), and thus cache namespace \
+                            //   not to be properly removed on purge of an \
+                            //   associated tag.
+                            // Also, count bumped keys. It may happen that \
+                            //   some tag keys are incorrectly removed by \
+                            //   Redis LRU system, as it is probabilistic \
+                            //   and thus might sample some keys incorrectly.
+                            // The conditions explained above only happens on \
+                            //   Redis instances with used memory going over \
+                            //   the threshold of the max memory policy.
+                            let tags_count = tags.len();
+
+                            match redis::cmd("TOUCH")
+                                .arg(&tags)
+                                .query_async::<usize>(&mut connection)
+                                .await
+                            {
+                                Ok(bump_count) => {
+                                    // Partial bump count? Consider cache as non-existing
+                                    if bump_count < tags_count {
+                                        info!(
+                                            "got only partial tag count: {}/{}",
+                                            bump_count, tags_count
+                                        );
+
+                                        return Ok((None, public_route));
                                     }
                                 }
+                                Err(err) => {
+                                    error!("error bumping access time of tags: {}", err);
+                                }
                             }
                         }
 
                         // Decode raw bytes to string
                         if let Ok(fingerprint) = String::from_utf8(fingerprint_bytes) {
-                            Ok(Some((fingerprint, compressed)))
+                            Ok((
+                                Some(CacheStoreLookup::Meta(CacheStoreMeta {
+                                    key,
+                                    fingerprint,
+                                    compressed,
+                                    expires,
+                                    created,
+                                    modified,
+                                    revalidate,
+                                    validators,
+                                    tags,
+                                    public,
+                                })),
+                                public_route,
+                            ))
                         } else {
                             Err(CacheStoreError::Corrupted)
                         }
                     }
-                    (Some(Value::Nil), _, _) | (None, _, _) => Ok(None),
+                    Some(Value::Nil) | None => {
+                        // Route varies on request headers? Return its vary \
+                        //   spec, as the actual entry is stored as a variant
+                        match Self::parse_value_bytes(values_iter.last()) {
+                            Some(vary_bytes) => match String::from_utf8(vary_bytes) {
+                                Ok(vary) => Ok((Some(CacheStoreLookup::Vary(vary)), public_route)),
+                                Err(_) => Err(CacheStoreError::Corrupted),
+                            },
+                            None => Ok((None, public_route)),
+                        }
+                    }
                     _ => Err(CacheStoreError::Invalid),
                 }
             }
@@ -181,75 +347,161 @@ impl CacheStore {
         }
     }
 
-    pub async fn get_body(
+    pub async fn get_envelope(
         &self,
         key: String,
         compressed: bool,
-    ) -> Result<Option<String>, CacheStoreError> {
+        encoding: Option<CacheEncodingValue>,
+    ) -> Result<Option<CacheEnvelope>, CacheStoreError> {
         let mut connection = self.get_main_conn_unreliable().await?;
 
-        match connection.hget::<_, _, Value>(&key, KEY_BODY).await {
-            Ok(value) => match value {
-                Value::BulkString(body_bytes_raw) => {
-                    let body_bytes_result = if compressed {
-                        // Decompress raw bytes
-                        match zstd::decode_all(&body_bytes_raw[..]) {
-                            Ok(decompress_bytes) => {
-                                if body_bytes_raw.len() > 0 && decompress_bytes.len() == 0 {
-                                    error!("decompressed store value has empty body");
-
-                                    Err(())
-                                } else {
-                                    debug!(
-                                        "decompressed store value from {} bytes to {} bytes",
-                                        body_bytes_raw.len(),
-                                        decompress_bytes.len()
-                                    );
-
-                                    Ok(decompress_bytes)
-                                }
-                            }
-                            Err(err) => {
-                                error!("error decompressing store value: {}", err);
+        // Notice: a stored body that got compressed using zstd already is a \
+        //   'zstd' encoded body, thus it does not need to be encoded again.
+        let is_encoding_derived = compressed == true && encoding == Some(CacheEncodingValue::Zstd);
 
-                                Err(())
-                            }
-                        }
-                    } else {
-                        Ok(body_bytes_raw)
-                    };
+        // Client accepts an encoding? Acquire its stored encoded body (if any)
+        if let Some(encoding) = encoding.filter(|_| is_encoding_derived == false) {
+            if let Some((status_bytes, headers_bytes, body_bytes)) = Self::get_envelope_fields(
+                &mut connection,
+                &key,
+                Self::get_encoded_body_field(encoding),
+            )
+            .await?
+            {
+                debug!("acquired {} encoded store value", encoding.to_str());
 
-                    // Decode raw bytes to string
-                    if let Ok(body_bytes) = body_bytes_result {
-                        if let Ok(body) = String::from_utf8(body_bytes) {
-                            Ok(Some(body))
-                        } else {
-                            Err(CacheStoreError::Corrupted)
-                        }
+                let mut envelope =
+                    Self::decode_envelope(&status_bytes, &headers_bytes, body_bytes)?;
+
+                envelope.encoding = Some(encoding);
+
+                return Ok(Some(envelope));
+            }
+        }
+
+        match Self::get_envelope_fields(&mut connection, &key, KEY_BODY).await? {
+            Some((status_bytes, headers_bytes, body_bytes_raw)) => {
+                if is_encoding_derived == true {
+                    let mut envelope =
+                        Self::decode_envelope(&status_bytes, &headers_bytes, body_bytes_raw)?;
+
+                    // Serve compressed body as-is? (if the response can be \
+                    //   encoded at all, otherwise decompress it)
+                    if CacheEncoding::is_compressible(&envelope.status, &envelope.header_map())
+                        == true
+                    {
+                        envelope.encoding = Some(CacheEncodingValue::Zstd);
                     } else {
-                        Err(CacheStoreError::Failed)
+                        envelope.body = Self::decompress_body(&envelope.body)
+                            .map(Bytes::from)
+                            .or(Err(CacheStoreError::Failed))?;
                     }
+
+                    Ok(Some(envelope))
+                } else {
+                    let body_bytes = if compressed == true {
+                        Self::decompress_body(&body_bytes_raw).or(Err(CacheStoreError::Failed))?
+                    } else {
+                        body_bytes_raw
+                    };
+
+                    // Decode raw bytes to envelope (body may hold binary data)
+                    Self::decode_envelope(&status_bytes, &headers_bytes, body_bytes).map(Some)
                 }
-                Value::Nil => Ok(None),
-                _ => Err(CacheStoreError::Invalid),
-            },
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_envelope_fields(
+        connection: &mut ConnectionManager,
+        key: &str,
+        body_field: &str,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>, Vec<u8>)>, CacheStoreError> {
+        match connection
+            .hmget::<_, _, Vec<Value>>(key, &[KEY_STATUS, KEY_HEADERS, body_field])
+            .await
+        {
+            Ok(values) => {
+                let mut values_iter = values.into_iter();
+
+                match (values_iter.next(), values_iter.next(), values_iter.next()) {
+                    (
+                        Some(Value::BulkString(status_bytes)),
+                        Some(Value::BulkString(headers_bytes)),
+                        Some(Value::BulkString(body_bytes)),
+                    ) => Ok(Some((status_bytes, headers_bytes, body_bytes))),
+                    (Some(Value::Nil), _, _)
+                    | (_, Some(Value::Nil), _)
+                    | (_, _, Some(Value::Nil))
+                    | (None, _, _) => Ok(None),
+                    _ => Err(CacheStoreError::Invalid),
+                }
+            }
             _ => Err(CacheStoreError::Failed),
         }
     }
 
+    fn decode_envelope(
+        status_bytes: &[u8],
+        headers_bytes: &[u8],
+        body_bytes: Vec<u8>,
+    ) -> Result<CacheEnvelope, CacheStoreError> {
+        CacheEnvelope::decode(status_bytes, headers_bytes, Bytes::from(body_bytes))
+            .ok_or(CacheStoreError::Corrupted)
+    }
+
+    fn decompress_body(body_bytes_raw: &[u8]) -> Result<Vec<u8>, ()> {
+        match zstd::decode_all(body_bytes_raw) {
+            Ok(decompress_bytes) => {
+                if body_bytes_raw.len() > 0 && decompress_bytes.len() == 0 {
+                    error!("decompressed store value has empty body");
+
+                    Err(())
+                } else {
+                    debug!(
+                        "decompressed store value from {} bytes to {} bytes",
+                        body_bytes_raw.len(),
+                        decompress_bytes.len()
+                    );
+
+                    Ok(decompress_bytes)
+                }
+            }
+            Err(err) => {
+                error!("error decompressing store value: {}", err);
+
+                Err(())
+            }
+        }
+    }
+
     pub async fn set(
         &self,
         key: String,
         key_mask: String,
-        value: String,
+        envelope: CacheEnvelope,
         fingerprint: String,
-        ttl: usize,
+        expiry: CacheStoreExpiry,
         key_tags: Vec<(String, String)>,
     ) -> CacheWriteResult {
-        let body_size = value.len();
+        let body_size = envelope.body.len();
 
-        // Cap TTL to 'max_key_expiration'
-        let ttl_cap = cmp::min(ttl, APP_CONF.redis.max_key_expiration);
+        // Process expiration values
+        let (ttl_store, expires_value, revalidate_value) = expiry.process();
+
+        // Generate creation time value
+        let created_value = time_now().to_string();
+
+        // Generate modification time value (time at which the stored \
+        //   response was last modified, defaults to creation time)
+        let modified_value = envelope
+            .modified
+            .map(|modified| modified.to_string())
+            .unwrap_or_else(|| created_value.to_owned());
+
+        // Acquire validators from response headers
+        let validators = CacheStoreValidators::from_envelope(&envelope);
 
         // Check if we should compress the body
         let compress_body =
@@ -257,9 +509,9 @@ impl CacheStore {
 
         // Compress value?
         let store_value_bytes_result = if compress_body == true {
-            zstd::encode_all(value.as_bytes(), APP_CONF.cache.compress_level)
+            zstd::encode_all(&envelope.body[..], APP_CONF.cache.compress_level)
         } else {
-            Ok(value.into_bytes())
+            Ok(envelope.body.to_vec())
         };
 
         let store_value_bytes = match store_value_bytes_result {
@@ -286,7 +538,9 @@ impl CacheStore {
         //   this maximum size to act as a safety limit for the storage, so \
         //   we need to check on the final binary data that will be sent off \
         //   to the storage.
-        if store_value_bytes.len() > APP_CONF.redis.max_key_size {
+        let store_headers_bytes = envelope.encode_headers();
+
+        if store_value_bytes.len() + store_headers_bytes.len() > APP_CONF.redis.max_key_size {
             return Err((CacheStoreError::TooLarge, fingerprint));
         }
 
@@ -310,16 +564,42 @@ impl CacheStore {
                 .hset_multiple(
                     &key,
                     &[
+                        (KEY_VERSION, ENVELOPE_VERSION),
                         (KEY_FINGERPRINT, fingerprint.as_bytes()),
                         (KEY_TAGS, key_tag_masks.join(KEY_TAGS_SEPARATOR).as_bytes()),
                         (KEY_COMPRESSED, compress_value_bytes),
+                        (KEY_EXPIRES, expires_value.as_bytes()),
+                        (KEY_CREATED, created_value.as_bytes()),
+                        (KEY_MODIFIED, modified_value.as_bytes()),
+                        (KEY_REVALIDATE, revalidate_value.as_bytes()),
+                        (KEY_ETAG, validators.etag_bytes()),
+                        (KEY_LAST_MODIFIED, validators.last_modified_bytes()),
+                        (KEY_STATUS, &envelope.encode_status()),
+                        (KEY_HEADERS, &store_headers_bytes),
                         (KEY_BODY, &store_value_bytes),
                     ],
                 )
                 .ignore();
         }
 
-        pipeline.expire(&key, ttl_cap as i64).ignore();
+        // Clear any vary spec formerly stored for this route (ie. if the API \
+        //   stopped varying its responses), any encoded body derived from \
+        //   the former body, as well as any former public mark (public \
+        //   entries get marked again afterwards)
+        pipeline
+            .hdel(
+                &key,
+                &[
+                    KEY_VARY,
+                    KEY_BODY_BROTLI,
+                    KEY_BODY_ZSTD,
+                    KEY_BODY_GZIP,
+                    KEY_PUBLIC,
+                ],
+            )
+            .ignore();
+
+        pipeline.expire(&key, ttl_store as i64).ignore();
 
         for key_tag in &key_tags {
             pipeline.sadd(&key_tag.0, &key_mask).ignore();
@@ -339,6 +619,165 @@ impl CacheStore {
         }
     }
 
+    pub async fn set_vary(
+        &self,
+        key: String,
+        vary: String,
+        expiry: &CacheStoreExpiry,
+    ) -> Result<(), CacheStoreError> {
+        // Retain vary spec for as long as the variant that comes with it
+        let (ttl_store, _, _) = expiry.process();
+
+        let mut pipeline = redis::pipe();
+
+        // Replace any entry formerly stored for this route (ie. if the API \
+        //   started varying its responses)
+        pipeline.del(&key).ignore();
+        pipeline.hset(&key, KEY_VARY, &vary).ignore();
+        pipeline.expire(&key, ttl_store as i64).ignore();
+
+        let mut connection = self.get_main_conn_unreliable().await?;
+
+        pipeline
+            .query_async::<()>(&mut connection)
+            .await
+            .map_err(|err| {
+                error!("got store error: {}", err);
+
+                CacheStoreError::Failed
+            })
+    }
+
+    pub async fn set_scope(
+        &self,
+        key: String,
+        scope_key: String,
+        expiry: &CacheStoreExpiry,
+    ) -> Result<(), CacheStoreError> {
+        let (ttl_store, _, _) = expiry.process();
+
+        let mut pipeline = redis::pipe();
+
+        // Mark entry as public (so that it can be served to any requester), \
+        //   and its route as having a public entry (so that requesters look \
+        //   it up), for as long as the entry is retained
+        pipeline.hset(&key, KEY_PUBLIC, VALUE_PUBLIC_YES).ignore();
+        pipeline
+            .set_ex(&scope_key, VALUE_PUBLIC_YES, ttl_store as u64)
+            .ignore();
+
+        let mut connection = self.get_main_conn_unreliable().await?;
+
+        pipeline
+            .query_async::<()>(&mut connection)
+            .await
+            .map_err(|err| {
+                error!("got store error: {}", err);
+
+                CacheStoreError::Failed
+            })
+    }
+
+    pub async fn refresh(
+        &self,
+        key: String,
+        envelope: &CacheEnvelope,
+        fingerprint: String,
+        expiry: CacheStoreExpiry,
+        tags: &[String],
+    ) -> CacheWriteResult {
+        // Process expiration values
+        let (ttl_store, expires_value, revalidate_value) = expiry.process();
+
+        // Generate creation time value
+        let created_value = time_now().to_string();
+
+        // Acquire validators from response headers
+        let validators = CacheStoreValidators::from_envelope(envelope);
+
+        let mut pipeline = redis::pipe();
+
+        // Append update command (the stored body is left untouched, as it \
+        //   has not been modified)
+        pipeline
+            .hset_multiple(
+                &key,
+                &[
+                    (KEY_FINGERPRINT, fingerprint.as_bytes()),
+                    (KEY_EXPIRES, expires_value.as_bytes()),
+                    (KEY_CREATED, created_value.as_bytes()),
+                    (KEY_REVALIDATE, revalidate_value.as_bytes()),
+                    (KEY_ETAG, validators.etag_bytes()),
+                    (KEY_LAST_MODIFIED, validators.last_modified_bytes()),
+                    (KEY_HEADERS, &envelope.encode_headers()),
+                ],
+            )
+            .ignore();
+
+        // Update modification time? (if the API tells when it last modified \
+        //   the response, as the stored body has not been modified)
+        if let Some(modified) = envelope.modified {
+            pipeline.hset(&key, KEY_MODIFIED, modified).ignore();
+        }
+
+        pipeline.expire(&key, ttl_store as i64).ignore();
+
+        for tag in tags {
+            pipeline
+                .expire(tag, APP_CONF.redis.max_key_expiration as i64)
+                .ignore();
+        }
+
+        match self.get_main_conn_unreliable().await {
+            Ok(mut connection) => match pipeline.query_async::<()>(&mut connection).await {
+                Ok(_) => Ok(fingerprint),
+                Err(err) => {
+                    error!("got store error: {}", err);
+
+                    Err((CacheStoreError::Failed, fingerprint))
+                }
+            },
+            Err(err) => Err((err, fingerprint)),
+        }
+    }
+
+    pub async fn set_encoded(
+        &self,
+        key: String,
+        fingerprint: String,
+        encoding: CacheEncodingValue,
+        body: Bytes,
+    ) -> Result<(), CacheStoreError> {
+        // Ensure value is not larger than 'max_key_size'
+        if body.len() > APP_CONF.redis.max_key_size {
+            return Err(CacheStoreError::TooLarge);
+        }
+
+        let mut connection = self.get_scripts_conn().await?;
+
+        // Notice: the encoded body is stored only if the entry still holds \
+        //   the body it was derived from, as it may have been replaced since.
+        redis::Script::new(
+            r#"
+                if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+                    redis.call('HSET', KEYS[1], ARGV[3], ARGV[4])
+                end
+            "#,
+        )
+        .key(key)
+        .arg(KEY_FINGERPRINT)
+        .arg(fingerprint)
+        .arg(Self::get_encoded_body_field(encoding))
+        .arg(&body[..])
+        .invoke_async::<()>(&mut connection)
+        .await
+        .map_err(|err| {
+            error!("got store error: {}", err);
+
+            CacheStoreError::Failed
+        })
+    }
+
     pub async fn purge_tag(
         &self,
         variant: &CachePurgeVariant,
@@ -356,6 +795,33 @@ impl CacheStore {
             .or(Err(CacheStoreError::Failed))
     }
 
+    fn get_encoded_body_field(encoding: CacheEncodingValue) -> &'static str {
+        match encoding {
+            CacheEncodingValue::Brotli => KEY_BODY_BROTLI,
+            CacheEncodingValue::Zstd => KEY_BODY_ZSTD,
+            CacheEncodingValue::Gzip => KEY_BODY_GZIP,
+        }
+    }
+
+    fn parse_value_bytes(value: Option<Value>) -> Option<Vec<u8>> {
+        match value {
+            Some(Value::BulkString(value_bytes)) => Some(value_bytes),
+            _ => None,
+        }
+    }
+
+    fn parse_value_header(value_bytes: Option<Vec<u8>>) -> Option<HeaderValue> {
+        value_bytes
+            .filter(|value| value.is_empty() == false)
+            .and_then(|value| HeaderValue::from_bytes(&value).ok())
+    }
+
+    fn parse_value_number(value_bytes: Option<Vec<u8>>) -> Option<u64> {
+        value_bytes
+            .and_then(|value| String::from_utf8(value).ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    }
+
     async fn get_main_conn_unreliable(&self) -> Result<ConnectionManager, CacheStoreError> {
         // In the event of a Redis failure, 'get_main_conn_unreliable' allows \
         //   a full pass-through to be performed, thus ensuring service \
@@ -438,6 +904,120 @@ impl CacheStore {
     }
 }
 
+impl CacheStoreExpiry {
+    fn process(&self) -> (usize, String, String) {
+        // Cap TTL to 'max_key_expiration'
+        let ttl_cap = cmp::min(self.ttl, APP_CONF.redis.max_key_expiration);
+
+        // Cap grace period to 'max_key_expiration' (separately from TTL, so \
+        //   that a TTL at its maximum value does not void the grace period)
+        let grace_cap = cmp::min(self.grace, APP_CONF.redis.max_key_expiration);
+
+        if grace_cap < self.grace {
+            warn!(
+                "capped grace period from {} to {} seconds (max_key_expiration)",
+                self.grace, grace_cap
+            );
+        }
+
+        // Cap revalidate window to 'max_key_expiration' (same as grace period)
+        let revalidate_cap = cmp::min(self.revalidate, APP_CONF.redis.max_key_expiration);
+
+        if revalidate_cap < self.revalidate {
+            warn!(
+                "capped revalidate window from {} to {} seconds (max_key_expiration)",
+                self.revalidate, revalidate_cap
+            );
+        }
+
+        // Retain key past its TTL for the grace period or revalidate window \
+        //   (whichever is the longest), so that it can still be served as stale \
+        //   if the API goes down, or while it gets refreshed in the background
+        let ttl_store = ttl_cap + cmp::max(grace_cap, revalidate_cap);
+
+        // Generate expiration time value (time after which key is stale)
+        let expires_value = (time_now() + ttl_cap as u64).to_string();
+
+        // Generate revalidate window value
+        let revalidate_value = revalidate_cap.to_string();
+
+        (ttl_store, expires_value, revalidate_value)
+    }
+}
+
+impl CacheStoreValidators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn from_envelope(envelope: &CacheEnvelope) -> CacheStoreValidators {
+        let find_header = |name: header::HeaderName| {
+            envelope
+                .headers
+                .iter()
+                .find(|(header_name, _)| *header_name == name)
+                .map(|(_, header_value)| header_value.to_owned())
+        };
+
+        CacheStoreValidators {
+            etag: find_header(header::ETAG),
+            last_modified: find_header(header::LAST_MODIFIED),
+        }
+    }
+
+    fn etag_bytes(&self) -> &[u8] {
+        self.etag
+            .as_ref()
+            .map(|value| value.as_bytes())
+            .unwrap_or(&[])
+    }
+
+    fn last_modified_bytes(&self) -> &[u8] {
+        self.last_modified
+            .as_ref()
+            .map(|value| value.as_bytes())
+            .unwrap_or(&[])
+    }
+}
+
+impl CacheStoreMeta {
+    pub fn expired_for(&self) -> Option<u64> {
+        self.expired_for_at(time_now())
+    }
+
+    pub fn age(&self) -> Option<u64> {
+        self.age_at(time_now())
+    }
+
+    pub fn can_revalidate(&self) -> bool {
+        self.can_revalidate_at(time_now())
+    }
+
+    fn expired_for_at(&self, now: u64) -> Option<u64> {
+        // Acquire for how long the key has been stale (if it is stale)
+        self.expires.and_then(|expires| {
+            if now >= expires {
+                Some(now - expires)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn age_at(&self, now: u64) -> Option<u64> {
+        // Acquire for how long the key has been stored (or refreshed)
+        self.created.map(|created| now.saturating_sub(created))
+    }
+
+    fn can_revalidate_at(&self, now: u64) -> bool {
+        // Stale keys can be served while being refreshed in the background, \
+        //   as long as they are within their revalidate window
+        self.expired_for_at(now)
+            .map(|expired_for| expired_for < self.revalidate)
+            .unwrap_or(false)
+    }
+}
+
 impl CachePurgeVariant {
     fn get_script(&self) -> &'static str {
         // Notice: there is a limit of 1000 purgeable tags per bucket. Purging a lot of tags at \
@@ -462,3 +1042,60 @@ impl CachePurgeVariant {
         }
     }
 }
+
+pub fn time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_meta(expires: Option<u64>) -> CacheStoreMeta {
+        CacheStoreMeta {
+            key: "bloom:0:c:dc56d17a:e6a8b05d".to_string(),
+            fingerprint: "7e2a5bbbc8d37d3a".to_string(),
+            compressed: false,
+            expires,
+            created: Some(400),
+            modified: Some(400),
+            revalidate: 60,
+            validators: CacheStoreValidators::default(),
+            tags: Vec::new(),
+            public: false,
+        }
+    }
+
+    #[test]
+    fn it_checks_fresh_meta() {
+        assert_eq!(make_meta(Some(1000)).expired_for_at(900), None);
+    }
+
+    #[test]
+    fn it_checks_expired_meta() {
+        assert_eq!(make_meta(Some(1000)).expired_for_at(1000), Some(0));
+        assert_eq!(make_meta(Some(1000)).expired_for_at(1030), Some(30));
+    }
+
+    #[test]
+    fn it_checks_meta_without_expiration() {
+        assert_eq!(make_meta(None).expired_for_at(1000), None);
+    }
+
+    #[test]
+    fn it_checks_meta_can_revalidate() {
+        assert!(!make_meta(Some(1000)).can_revalidate_at(900));
+        assert!(make_meta(Some(1000)).can_revalidate_at(1030));
+        assert!(!make_meta(Some(1000)).can_revalidate_at(1060));
+        assert!(!make_meta(None).can_revalidate_at(1030));
+    }
+
+    #[test]
+    fn it_checks_meta_age() {
+        assert_eq!(make_meta(Some(1000)).age_at(1000), Some(600));
+        assert_eq!(make_meta(Some(1000)).age_at(300), Some(0));
+    }
+}
@@ -0,0 +1,252 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::borrow::Cow;
+
+use hyper::header::{self, HeaderMap};
+
+use super::encoding::CacheEncoding;
+use super::route::CacheRoute;
+use crate::header::response_vary::HeaderResponseBloomResponseVary;
+
+pub struct CacheVary;
+
+#[derive(Debug, PartialEq)]
+pub enum CacheVarySpec {
+    Empty,
+    Wildcard,
+    Headers(Vec<String>),
+}
+
+static VARY_SEPARATOR: &'static str = ",";
+static VARY_WILDCARD: &'static str = "*";
+static VARY_ACCEPT_ENCODING: &'static str = "accept-encoding";
+
+impl CacheVary {
+    pub fn from_response(headers: &HeaderMap, is_negotiating_encoding: bool) -> CacheVarySpec {
+        // Notice: 'Bloom-Response-Vary' takes precedence over 'Vary', so that \
+        //   the API can vary cached responses on another set of request \
+        //   headers than the one it announces to clients (or on none at all).
+        let mut names: Vec<String> =
+            match headers.get(HeaderResponseBloomResponseVary::header_name()) {
+                Some(value) => HeaderResponseBloomResponseVary::from_header_value(value)
+                    .map(|vary| vary.0)
+                    .unwrap_or_default(),
+                None => headers
+                    .get_all(header::VARY)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| name.is_empty() == false)
+                    .collect(),
+            };
+
+        // Response encoded by the API? It must be cached per set of accepted \
+        //   encodings, whatever the API tells. Otherwise, if Bloom encodes \
+        //   responses itself, the same cached response serves all encodings.
+        if CacheEncoding::is_encoded(headers) == true {
+            names.push(VARY_ACCEPT_ENCODING.to_string());
+        } else if is_negotiating_encoding == true {
+            names.retain(|name| name != VARY_ACCEPT_ENCODING);
+        }
+
+        Self::make_spec(names)
+    }
+
+    pub fn from_stored(value: &str) -> CacheVarySpec {
+        Self::make_spec(
+            value
+                .split(VARY_SEPARATOR)
+                .filter(|name| name.is_empty() == false)
+                .map(|name| name.to_string())
+                .collect(),
+        )
+    }
+
+    pub fn to_stored(names: &[String]) -> String {
+        names.join(VARY_SEPARATOR)
+    }
+
+    pub fn hash(names: &[String], headers: &HeaderMap) -> String {
+        // Notice: all occurrences of a request header are accounted for, and \
+        //   a missing header is told apart from an empty one.
+        let variant_raw = names
+            .iter()
+            .map(|name| {
+                // Notice: accepted encodings are normalized, as clients list \
+                //   them in many equivalent ways.
+                let values = if name == VARY_ACCEPT_ENCODING {
+                    Some(CacheEncoding::normalize_accept(headers))
+                        .filter(|value| value.is_empty() == false)
+                        .map(Cow::Owned)
+                        .into_iter()
+                        .collect::<Vec<_>>()
+                } else {
+                    headers
+                        .get_all(name.as_str())
+                        .iter()
+                        .map(|value| String::from_utf8_lossy(value.as_bytes()))
+                        .collect::<Vec<_>>()
+                };
+
+                if values.is_empty() == true {
+                    name.to_owned()
+                } else {
+                    format!("{}={}", name, values.join(","))
+                }
+            })
+            .collect::<Vec<String>>();
+
+        CacheRoute::hash(&format!("[{}]", variant_raw.join("|")))
+    }
+
+    fn make_spec(mut names: Vec<String>) -> CacheVarySpec {
+        // A response that varies on anything cannot be matched against \
+        //   further requests (RFC 9111, section 4.1)
+        if names.iter().any(|name| name == VARY_WILDCARD) {
+            return CacheVarySpec::Wildcard;
+        }
+
+        // Normalize names, so that the same set of request headers always \
+        //   yields the same variants
+        names.sort();
+        names.dedup();
+
+        if names.is_empty() == true {
+            CacheVarySpec::Empty
+        } else {
+            CacheVarySpec::Headers(names)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn make_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+
+        for (name, value) in headers {
+            header_map.append(*name, HeaderValue::from_static(value));
+        }
+
+        header_map
+    }
+
+    #[test]
+    fn it_parses_vary_spec() {
+        assert_eq!(
+            CacheVary::from_response(&make_headers(&[("content-type", "text/plain")]), false),
+            CacheVarySpec::Empty
+        );
+        assert_eq!(
+            CacheVary::from_response(
+                &make_headers(&[("vary", "Accept-Language, Accept"), ("vary", "accept")]),
+                false
+            ),
+            CacheVarySpec::Headers(vec!["accept".to_string(), "accept-language".to_string()])
+        );
+        assert_eq!(
+            CacheVary::from_response(&make_headers(&[("vary", "Accept, *")]), false),
+            CacheVarySpec::Wildcard
+        );
+        assert_eq!(
+            CacheVary::from_stored("accept,accept-language"),
+            CacheVarySpec::Headers(vec!["accept".to_string(), "accept-language".to_string()])
+        );
+    }
+
+    #[test]
+    fn it_prefers_bloom_vary_spec() {
+        assert_eq!(
+            CacheVary::from_response(
+                &make_headers(&[("vary", "Accept"), ("bloom-response-vary", "X-Locale")]),
+                false
+            ),
+            CacheVarySpec::Headers(vec!["x-locale".to_string()])
+        );
+        assert_eq!(
+            CacheVary::from_response(
+                &make_headers(&[("vary", "*"), ("bloom-response-vary", "")]),
+                false
+            ),
+            CacheVarySpec::Empty
+        );
+    }
+
+    #[test]
+    fn it_varies_on_accept_encoding() {
+        assert_eq!(
+            CacheVary::from_response(&make_headers(&[("vary", "Accept, Accept-Encoding")]), true),
+            CacheVarySpec::Headers(vec!["accept".to_string()])
+        );
+        assert_eq!(
+            CacheVary::from_response(&make_headers(&[("vary", "Accept, Accept-Encoding")]), false),
+            CacheVarySpec::Headers(vec!["accept".to_string(), "accept-encoding".to_string()])
+        );
+        assert_eq!(
+            CacheVary::from_response(
+                &make_headers(&[("content-encoding", "gzip"), ("bloom-response-vary", "")]),
+                true
+            ),
+            CacheVarySpec::Headers(vec!["accept-encoding".to_string()])
+        );
+
+        let names = vec!["accept-encoding".to_string()];
+
+        assert_eq!(
+            CacheVary::hash(&names, &make_headers(&[("accept-encoding", "gzip, br")])),
+            CacheVary::hash(
+                &names,
+                &make_headers(&[("accept-encoding", "br;q=0.9, deflate, gzip")])
+            )
+        );
+        assert_eq!(
+            CacheVary::hash(&names, &make_headers(&[("accept-encoding", "deflate")])),
+            CacheVary::hash(&names, &make_headers(&[]))
+        );
+    }
+
+    #[test]
+    fn it_hashes_vary_variants() {
+        let names = vec!["accept".to_string(), "accept-language".to_string()];
+
+        let hash_fr = CacheVary::hash(
+            &names,
+            &make_headers(&[("accept", "text/html"), ("accept-language", "fr")]),
+        );
+
+        assert_eq!(
+            hash_fr,
+            CacheVary::hash(
+                &names,
+                &make_headers(&[
+                    ("accept-language", "fr"),
+                    ("accept", "text/html"),
+                    ("cookie", "a=1")
+                ])
+            )
+        );
+        assert_ne!(
+            hash_fr,
+            CacheVary::hash(
+                &names,
+                &make_headers(&[("accept", "text/html"), ("accept-language", "en")])
+            )
+        );
+        assert_ne!(
+            CacheVary::hash(&names, &make_headers(&[("accept", "text/html")])),
+            CacheVary::hash(
+                &names,
+                &make_headers(&[("accept", "text/html"), ("accept-language", "")])
+            )
+        );
+    }
+}
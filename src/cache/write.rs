@@ -4,17 +4,31 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use std::cmp;
 use std::future::Future;
 use std::pin::Pin;
+use std::time::UNIX_EPOCH;
 
+use bytes::Bytes;
 use farmhash;
 use http_body_util::BodyExt;
-use hyper::{HeaderMap, Method, StatusCode, Version};
+use hyper::header;
+use hyper::{HeaderMap, Method, StatusCode};
 
 use super::check::CacheCheck;
-use super::route::CacheRoute;
+use super::encoding::CacheEncodingValue;
+use super::envelope::CacheEnvelope;
+use super::policy::{CachePolicy, CachePolicyTTL};
+use super::route::{CacheRoute, ROUTE_AUTH_PUBLIC};
+use super::store::{time_now, CacheStoreExpiry, CacheStoreMeta};
+use super::vary::{CacheVary, CacheVarySpec};
 use crate::header::janitor::HeaderJanitor;
 use crate::header::response_buckets::HeaderResponseBloomResponseBuckets;
+use crate::header::response_grace::HeaderResponseBloomResponseGrace;
+use crate::header::response_revalidate::HeaderResponseBloomResponseRevalidate;
+use crate::header::response_scope::{
+    HeaderResponseBloomResponseScope, HeaderResponseBloomResponseScopeValue,
+};
 use crate::header::response_ttl::HeaderResponseBloomResponseTTL;
 use crate::proxy::serve::ProxyServeError;
 use crate::APP_CACHE_STORE;
@@ -23,8 +37,9 @@ use crate::APP_CONF;
 pub struct CacheWrite;
 
 pub struct CacheWriteResult {
-    pub body: Result<String, Option<String>>,
+    pub body: Result<Bytes, Option<Bytes>>,
     pub fingerprint: Option<String>,
+    pub modified: Option<u64>,
     pub status: StatusCode,
     pub headers: HeaderMap,
 }
@@ -39,7 +54,7 @@ impl CacheWrite {
         auth_hash: String,
         shard: u8,
         method: Method,
-        version: Version,
+        request_headers: HeaderMap,
         status: StatusCode,
         mut headers: HeaderMap,
         body: B,
@@ -50,140 +65,351 @@ impl CacheWrite {
         B::Error: std::error::Error + Send + Sync + 'static,
     {
         Box::pin(async move {
-            let bytes = body
+            let body_value = body
                 .collect()
                 .await
                 .map_err(|err| -> ProxyServeError { Box::new(err) })?
                 .to_bytes();
 
-            let body_result = String::from_utf8(bytes.to_vec());
-
-            if let Ok(body_value) = body_result {
-                debug!("checking whether to write cache for key: {}", &key);
-
-                if APP_CONF.cache.disable_write == false
-                    && CacheCheck::from_response(&method, &status, &headers) == true
-                {
-                    debug!("key: {} cacheable, writing cache", &key);
-
-                    // Acquire bucket from response, or fallback to no bucket
-                    let mut key_tags =
-                        match headers.get(HeaderResponseBloomResponseBuckets::header_name()) {
-                            None => Vec::new(),
-                            Some(value) => {
-                                match HeaderResponseBloomResponseBuckets::from_header_value(value) {
-                                    None => Vec::new(),
-                                    Some(buckets) => buckets
-                                        .0
-                                        .iter()
-                                        .map(|value| {
-                                            CacheRoute::gen_key_bucket_from_hash(
-                                                shard,
-                                                &CacheRoute::hash(value),
-                                            )
-                                        })
-                                        .collect::<Vec<(String, String)>>(),
-                                }
-                            }
-                        };
+            debug!("checking whether to write cache for key: {}", &key);
+
+            let honor_cache_control = Self::is_honoring_cache_control(shard);
 
-                    key_tags.push(CacheRoute::gen_key_auth_from_hash(shard, &auth_hash));
+            if APP_CONF.cache.disable_write == false
+                && CacheCheck::from_response(&method, &status, &headers, honor_cache_control)
+                    == true
+            {
+                debug!("key: {} cacheable, writing cache", &key);
 
-                    // Acquire TTL from response, or fallback to default TTL
-                    let ttl = match headers.get(HeaderResponseBloomResponseTTL::header_name()) {
-                        None => APP_CONF.cache.ttl_default,
+                // Acquire bucket from response, or fallback to no bucket
+                let mut key_tags =
+                    match headers.get(HeaderResponseBloomResponseBuckets::header_name()) {
+                        None => Vec::new(),
                         Some(value) => {
-                            match HeaderResponseBloomResponseTTL::from_header_value(value) {
-                                None => APP_CONF.cache.ttl_default,
-                                Some(ttl) => ttl.0,
+                            match HeaderResponseBloomResponseBuckets::from_header_value(value) {
+                                None => Vec::new(),
+                                Some(buckets) => buckets
+                                    .0
+                                    .iter()
+                                    .map(|value| {
+                                        CacheRoute::gen_key_bucket_from_hash(
+                                            shard,
+                                            &CacheRoute::hash(value),
+                                        )
+                                    })
+                                    .collect::<Vec<(String, String)>>(),
                             }
                         }
                     };
 
-                    // Clean headers before they get stored
-                    HeaderJanitor::clean(&mut headers);
-
-                    // Generate storable value
-                    let body_string = format!(
-                        "{}\n{}\n{}",
-                        CacheWrite::generate_chain_banner(&version, &status),
-                        CacheWrite::generate_chain_headers(&headers),
-                        body_value
-                    );
-
-                    // Process value fingerprint
-                    let fingerprint = Self::process_body_fingerprint(&body_string);
-
-                    // Write to cache
-                    let result = APP_CACHE_STORE
-                        .set(key, key_mask, body_string, fingerprint, ttl, key_tags)
-                        .await;
-
-                    match result {
-                        Ok(fingerprint) => {
-                            debug!("wrote cache");
-
-                            Ok(CacheWriteResult {
-                                body: Ok(body_value),
-                                fingerprint: Some(fingerprint),
-                                status,
-                                headers,
-                            })
-                        }
-                        Err(forward) => {
-                            warn!("could not write cache because: {:?}", forward.0);
-
-                            Ok(CacheWriteResult {
-                                body: Err(Some(body_value)),
-                                fingerprint: Some(forward.1),
-                                status,
-                                headers,
-                            })
-                        }
+                // Acquire scope from response (responses are private by default)
+                let public = headers
+                    .get(HeaderResponseBloomResponseScope::header_name())
+                    .and_then(HeaderResponseBloomResponseScope::from_header_value)
+                    .map(|scope| scope.0 == HeaderResponseBloomResponseScopeValue::Public)
+                    .unwrap_or(false);
+
+                // Acquire route scope marker (marked if the response is public)
+                let scope_key = if public == true {
+                    CacheRoute::gen_key_scope(shard, &key)
+                } else {
+                    None
+                };
+
+                // Response is public? Write it to the auth-less namespace of \
+                //   the route, so that it gets shared by all requesters
+                let (key, key_mask, auth_hash) = if public == true {
+                    debug!("key: {} is public, writing to public namespace", &key);
+
+                    match CacheRoute::gen_key_cache_public(shard, &key) {
+                        Some((key_public, key_mask_public)) => (
+                            key_public,
+                            key_mask_public,
+                            CacheRoute::hash(ROUTE_AUTH_PUBLIC),
+                        ),
+                        None => (key, key_mask, auth_hash),
                     }
                 } else {
-                    debug!("key: {} not cacheable, ignoring", &key);
-
-                    // Not cacheable, ignore
-                    Ok(Self::result_cache_write_error(
-                        Some(body_value),
-                        status,
-                        headers,
-                    ))
+                    (key, key_mask, auth_hash)
+                };
+
+                key_tags.push(CacheRoute::gen_key_auth_from_hash(shard, &auth_hash));
+
+                // Acquire expiration values from response
+                let expiry = Self::process_expiry(&headers, honor_cache_control);
+
+                // Acquire vary spec from response (this must be done before \
+                //   cleaning headers, as 'Bloom-Response-Vary' is internal)
+                let vary = CacheVary::from_response(&headers, APP_CONF.proxy.compress_responses);
+
+                // Clean headers before they get stored
+                HeaderJanitor::clean(&mut headers);
+
+                // Generate storable value
+                // Notice: status, headers and body are stored as separate \
+                //   binary fields, so that the cached response gets served \
+                //   byte-identical to what the downstream API server sent.
+                let mut envelope = CacheEnvelope::new(status, &headers, body_value.clone());
+
+                // Never share cookies set for a requester with other \
+                //   requesters (though they are still sent to the requester)
+                if public == true {
+                    Self::strip_private_headers(&mut envelope);
+                }
+
+                // Process value fingerprint
+                let fingerprint = Self::process_body_fingerprint(&envelope);
+
+                // Acquire modification time from response, or fallback to \
+                //   the current time (the response gets served with it)
+                let modified = Self::process_modified(&headers).unwrap_or_else(time_now);
+
+                envelope.modified = Some(modified);
+
+                // Response varies on request headers? Store route vary spec, \
+                //   and write to the variant that matches request headers
+                let (key, key_mask) = if let CacheVarySpec::Headers(names) = vary {
+                    let vary_hash = CacheVary::hash(&names, &request_headers);
+
+                    debug!("key: {} varies on: {:?}, writing variant", &key, names);
+
+                    if let Err(err) = APP_CACHE_STORE
+                        .set_vary(key.to_owned(), CacheVary::to_stored(&names), &expiry)
+                        .await
+                    {
+                        warn!("could not write cache vary spec because: {:?}", err);
+                    }
+
+                    (
+                        CacheRoute::gen_key_vary(&key, &vary_hash),
+                        CacheRoute::gen_key_vary(&key_mask, &vary_hash),
+                    )
+                } else {
+                    (key, key_mask)
+                };
+
+                // Write to cache
+                let result = APP_CACHE_STORE
+                    .set(
+                        key.to_owned(),
+                        key_mask,
+                        envelope,
+                        fingerprint,
+                        expiry,
+                        key_tags,
+                    )
+                    .await;
+
+                // Mark entry and its route as public? (once entry is written)
+                if let (Ok(_), Some(scope_key)) = (&result, scope_key) {
+                    if let Err(err) = APP_CACHE_STORE.set_scope(key, scope_key, &expiry).await {
+                        warn!("could not write cache scope marker because: {:?}", err);
+                    }
+                }
+
+                match result {
+                    Ok(fingerprint) => {
+                        debug!("wrote cache");
+
+                        Ok(CacheWriteResult {
+                            body: Ok(body_value),
+                            fingerprint: Some(fingerprint),
+                            modified: Some(modified),
+                            status,
+                            headers,
+                        })
+                    }
+                    Err(forward) => {
+                        warn!("could not write cache because: {:?}", forward.0);
+
+                        Ok(CacheWriteResult {
+                            body: Err(Some(body_value)),
+                            fingerprint: Some(forward.1),
+                            modified: None,
+                            status,
+                            headers,
+                        })
+                    }
                 }
             } else {
-                error!("failed unwrapping body value for key: {}, ignoring", &key);
+                debug!("key: {} not cacheable, ignoring", &key);
 
                 // Not cacheable, ignore
-                Ok(Self::result_cache_write_error(None, status, headers))
+                Ok(Self::result_cache_write_error(
+                    Some(body_value),
+                    status,
+                    headers,
+                ))
             }
         })
     }
 
-    fn generate_chain_banner(version: &Version, status: &StatusCode) -> String {
-        format!("{:?} {}", *version, status)
+    fn strip_private_headers(envelope: &mut CacheEnvelope) {
+        envelope
+            .headers
+            .retain(|(name, _)| name != header::SET_COOKIE);
     }
 
-    fn generate_chain_headers(headers: &HeaderMap) -> String {
+    pub async fn refresh(
+        key: String,
+        shard: u8,
+        meta: &CacheStoreMeta,
+        mut envelope: CacheEnvelope,
+        mut headers: HeaderMap,
+    ) -> (String, CacheEnvelope) {
+        // Acquire expiration values from response
+        let expiry = Self::process_expiry(&headers, Self::is_honoring_cache_control(shard));
+
+        // Clean headers before they get merged into stored headers
+        HeaderJanitor::clean(&mut headers);
+
+        // Update stored headers with those from the 'Not Modified' response, \
+        //   leaving the stored body as-is
+        envelope.update_headers(&headers);
+
+        // Process value fingerprint (as headers may have changed)
+        let fingerprint = Self::process_body_fingerprint(&envelope);
+
+        // Update modification time? (if told by the API)
+        if let Some(modified) = Self::process_modified(&headers) {
+            envelope.modified = Some(modified);
+        }
+
+        if APP_CONF.cache.disable_write == true {
+            return (fingerprint, envelope);
+        }
+
+        debug!("refreshing cache for key: {}", &key);
+
+        match APP_CACHE_STORE
+            .refresh(key, &envelope, fingerprint, expiry, &meta.tags)
+            .await
+        {
+            Ok(fingerprint) => {
+                debug!("refreshed cache");
+
+                (fingerprint, envelope)
+            }
+            Err(forward) => {
+                warn!("could not refresh cache because: {:?}", forward.0);
+
+                (forward.1, envelope)
+            }
+        }
+    }
+
+    pub fn save_encoded(
+        key: String,
+        fingerprint: String,
+        encoding: CacheEncodingValue,
+        body: Bytes,
+    ) {
+        if APP_CONF.cache.disable_write == true {
+            return;
+        }
+
+        // Notice: the encoded body gets stored in the background, as the \
+        //   client does not need to wait for it to be stored.
+        tokio::spawn(async move {
+            debug!(
+                "writing {} encoded body to cache for key: {}",
+                encoding.to_str(),
+                &key
+            );
+
+            if let Err(err) = APP_CACHE_STORE
+                .set_encoded(key, fingerprint, encoding, body)
+                .await
+            {
+                warn!("could not write encoded body to cache because: {:?}", err);
+            }
+        });
+    }
+
+    fn is_honoring_cache_control(shard: u8) -> bool {
+        APP_CONF
+            .proxy
+            .find_shard(shard)
+            .map(|shard_config| shard_config.honor_cache_control)
+            .unwrap_or(false)
+    }
+
+    fn process_expiry(headers: &HeaderMap, honor_cache_control: bool) -> CacheStoreExpiry {
+        // Acquire TTL from response, or fallback to upstream caching policy \
+        //   TTL (if honored) or default TTL
+        let ttl = match headers.get(HeaderResponseBloomResponseTTL::header_name()) {
+            None => Self::process_default_ttl(headers, honor_cache_control),
+            Some(value) => match HeaderResponseBloomResponseTTL::from_header_value(value) {
+                None => Self::process_default_ttl(headers, honor_cache_control),
+                Some(ttl) => ttl.0,
+            },
+        };
+
+        // Acquire grace from response, or fallback to default grace
+        let grace = match headers.get(HeaderResponseBloomResponseGrace::header_name()) {
+            None => APP_CONF.cache.grace_default,
+            Some(value) => match HeaderResponseBloomResponseGrace::from_header_value(value) {
+                None => APP_CONF.cache.grace_default,
+                Some(grace) => grace.0,
+            },
+        };
+
+        // Acquire revalidate window from response, or fallback to \
+        //   default revalidate window
+        let revalidate = match headers.get(HeaderResponseBloomResponseRevalidate::header_name()) {
+            None => APP_CONF.cache.revalidate_default,
+            Some(value) => match HeaderResponseBloomResponseRevalidate::from_header_value(value) {
+                None => APP_CONF.cache.revalidate_default,
+                Some(revalidate) => revalidate.0,
+            },
+        };
+
+        CacheStoreExpiry {
+            ttl,
+            grace,
+            revalidate,
+        }
+    }
+
+    fn process_default_ttl(headers: &HeaderMap, honor_cache_control: bool) -> usize {
+        if honor_cache_control == true {
+            if let CachePolicyTTL::Value(ttl) = CachePolicy::from_response(headers) {
+                return ttl;
+            }
+        }
+
+        APP_CONF.cache.ttl_default
+    }
+
+    fn process_modified(headers: &HeaderMap) -> Option<u64> {
+        // Notice: modification times in the future are capped to the current \
+        //   time, as they are invalid (RFC 9110, section 8.8.2.1)
         headers
-            .iter()
-            .filter(|(name, _)| HeaderJanitor::is_contextual(name) == false)
-            .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap_or("")))
-            .collect()
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| cmp::min(modified.as_secs(), time_now()))
     }
 
-    fn process_body_fingerprint(body_string: &str) -> String {
-        format!("{:x}", farmhash::fingerprint64(body_string.as_bytes()))
+    fn process_body_fingerprint(envelope: &CacheEnvelope) -> String {
+        let mut fingerprint_bytes = envelope.encode_status();
+
+        fingerprint_bytes.extend_from_slice(&envelope.encode_headers());
+        fingerprint_bytes.extend_from_slice(&envelope.body);
+
+        format!("{:x}", farmhash::fingerprint64(&fingerprint_bytes))
     }
 
     fn result_cache_write_error(
-        body: Option<String>,
+        body: Option<Bytes>,
         status: StatusCode,
         headers: HeaderMap,
     ) -> CacheWriteResult {
         CacheWriteResult {
             body: Err(body),
             fingerprint: None,
+            modified: None,
             status,
             headers,
         }
@@ -203,7 +429,7 @@ mod tests {
             "90d52bc6".to_string(),
             0,
             Method::GET,
-            Version::HTTP_11,
+            HeaderMap::new(),
             StatusCode::OK,
             HeaderMap::new(),
             http_body_util::Full::<bytes::Bytes>::new(bytes::Bytes::new()),
@@ -211,4 +437,115 @@ mod tests {
         .await
         .is_err());
     }
+
+    #[test]
+    fn it_fingerprints_all_header_occurrences() {
+        let mut headers = HeaderMap::new();
+
+        headers.append(
+            hyper::header::LINK,
+            hyper::header::HeaderValue::from_static("</a>; rel=preload"),
+        );
+
+        let fingerprint_single = CacheWrite::process_body_fingerprint(&CacheEnvelope::new(
+            StatusCode::OK,
+            &headers,
+            Bytes::new(),
+        ));
+
+        headers.append(
+            hyper::header::LINK,
+            hyper::header::HeaderValue::from_static("</b>; rel=preload"),
+        );
+
+        let fingerprint_multiple = CacheWrite::process_body_fingerprint(&CacheEnvelope::new(
+            StatusCode::OK,
+            &headers,
+            Bytes::new(),
+        ));
+
+        assert_ne!(fingerprint_single, fingerprint_multiple);
+    }
+
+    #[test]
+    fn it_stores_all_header_occurrences() {
+        let mut headers = HeaderMap::new();
+
+        headers.append(
+            hyper::header::LINK,
+            hyper::header::HeaderValue::from_static("</a>; rel=preload"),
+        );
+        headers.append(
+            hyper::header::SET_COOKIE,
+            hyper::header::HeaderValue::from_static("a=1"),
+        );
+        headers.append(
+            hyper::header::LINK,
+            hyper::header::HeaderValue::from_static("</b>; rel=preload"),
+        );
+        headers.append(
+            hyper::header::SET_COOKIE,
+            hyper::header::HeaderValue::from_static("b=2"),
+        );
+        headers.append(
+            hyper::header::DATE,
+            hyper::header::HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+        headers.append(
+            HeaderResponseBloomResponseTTL::header_name(),
+            hyper::header::HeaderValue::from_static("60"),
+        );
+
+        HeaderJanitor::clean_with(&mut headers, &[]);
+
+        let envelope = CacheEnvelope::new(StatusCode::OK, &headers, Bytes::new());
+
+        let decoded = CacheEnvelope::decode(
+            &envelope.encode_status(),
+            &envelope.encode_headers(),
+            envelope.body.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            decoded
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_bytes()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("link", &b"</a>; rel=preload"[..]),
+                ("link", &b"</b>; rel=preload"[..]),
+                ("set-cookie", &b"a=1"[..]),
+                ("set-cookie", &b"b=2"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_strips_private_headers_from_public_entries() {
+        let mut headers = HeaderMap::new();
+
+        headers.append(
+            header::SET_COOKIE,
+            header::HeaderValue::from_static("session=a1b2"),
+        );
+        headers.append(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static("application/json"),
+        );
+
+        let mut envelope = CacheEnvelope::new(StatusCode::OK, &headers, Bytes::new());
+
+        CacheWrite::strip_private_headers(&mut envelope);
+
+        assert_eq!(
+            envelope
+                .headers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["content-type"]
+        );
+    }
 }
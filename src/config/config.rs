@@ -4,9 +4,12 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use std::fmt;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use regex::Regex;
+
 use super::defaults;
 use super::env_var;
 
@@ -27,20 +30,51 @@ pub struct ConfigServer {
     )]
     pub log_level: String,
 
-    #[serde(
-        default = "defaults::server_inet",
-        deserialize_with = "env_var::socket_addr"
-    )]
-    pub inet: SocketAddr,
+    #[serde(default = "defaults::server_inet", deserialize_with = "env_var::inet")]
+    pub inet: ConfigInet,
+
+    pub socket_permissions: Option<u32>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub socket_owner: Option<String>,
+
+    #[serde(default = "defaults::server_http2_max_concurrent_streams")]
+    pub http2_max_concurrent_streams: u32,
+
+    #[serde(default = "defaults::server_http2_initial_stream_window_size")]
+    pub http2_initial_stream_window_size: u32,
+
+    #[serde(default = "defaults::server_http2_initial_connection_window_size")]
+    pub http2_initial_connection_window_size: u32,
+
+    pub tls: Option<ConfigServerTls>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigServerTls {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+
+    #[serde(default = "defaults::server_tls_handshake_timeout_seconds")]
+    pub handshake_timeout_seconds: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigInet {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
 }
 
 #[derive(Deserialize)]
 pub struct ConfigControl {
-    #[serde(
-        default = "defaults::control_inet",
-        deserialize_with = "env_var::socket_addr"
-    )]
-    pub inet: SocketAddr,
+    #[serde(default = "defaults::control_inet", deserialize_with = "env_var::inet")]
+    pub inet: ConfigInet,
+
+    pub socket_permissions: Option<u32>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub socket_owner: Option<String>,
 
     #[serde(default = "defaults::control_tcp_timeout")]
     pub tcp_timeout: u64,
@@ -62,6 +96,27 @@ pub struct ConfigProxy {
     pub lock_tunnel_path: bool,
 
     pub lock_slowlog_millis: Option<u64>,
+
+    #[serde(default = "defaults::proxy_max_headers")]
+    pub max_headers: usize,
+
+    #[serde(default)]
+    pub strip_headers: Vec<String>,
+
+    #[serde(
+        default = "defaults::proxy_compress_responses",
+        deserialize_with = "env_var::bool"
+    )]
+    pub compress_responses: bool,
+
+    #[serde(default = "defaults::proxy_compress_responses_above_bytes")]
+    pub compress_responses_above_bytes: usize,
+
+    #[serde(
+        default = "defaults::proxy_tunnel_head_as_get",
+        deserialize_with = "env_var::bool"
+    )]
+    pub tunnel_head_as_get: bool,
 }
 
 #[derive(Deserialize)]
@@ -69,6 +124,9 @@ pub struct ConfigProxyShard {
     #[serde(default = "defaults::proxy_shard_shard")]
     pub shard: u8,
 
+    #[serde(default = "defaults::proxy_shard_scheme")]
+    pub scheme: ConfigProxyShardScheme,
+
     #[serde(
         default = "defaults::proxy_shard_host",
         deserialize_with = "env_var::str"
@@ -77,6 +135,213 @@ pub struct ConfigProxyShard {
 
     #[serde(default = "defaults::proxy_shard_port")]
     pub port: u16,
+
+    pub socket: Option<PathBuf>,
+
+    #[serde(default)]
+    pub backend: Vec<ConfigProxyShardBackend>,
+
+    #[serde(default = "defaults::proxy_shard_balance")]
+    pub balance: ConfigProxyShardBalance,
+
+    #[serde(
+        default = "defaults::proxy_shard_honor_cache_control",
+        deserialize_with = "env_var::bool"
+    )]
+    pub honor_cache_control: bool,
+
+    #[serde(
+        default = "defaults::proxy_shard_honor_request_cache_control",
+        deserialize_with = "env_var::bool"
+    )]
+    pub honor_request_cache_control: bool,
+
+    #[serde(default = "defaults::proxy_shard_cache_key")]
+    pub cache_key: ConfigProxyShardCacheKey,
+
+    #[serde(default)]
+    pub identity: Vec<ConfigProxyShardIdentity>,
+
+    pub jwt: Option<ConfigProxyShardJwt>,
+
+    pub tls: Option<ConfigProxyShardTls>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ConfigProxyShardScheme {
+    #[serde(rename = "http")]
+    Http,
+
+    #[serde(rename = "https")]
+    Https,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProxyShardBackend {
+    #[serde(
+        default = "defaults::proxy_shard_host",
+        deserialize_with = "env_var::str"
+    )]
+    pub host: String,
+
+    #[serde(default = "defaults::proxy_shard_port")]
+    pub port: u16,
+
+    pub socket: Option<PathBuf>,
+
+    #[serde(default = "defaults::proxy_shard_backend_weight")]
+    pub weight: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ConfigProxyShardBalance {
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+
+    #[serde(rename = "least_in_flight")]
+    LeastInFlight,
+
+    #[serde(rename = "random_two_choices")]
+    RandomTwoChoices,
+
+    #[serde(rename = "consistent_hash")]
+    ConsistentHash,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProxyShardCacheKey {
+    #[serde(
+        default = "defaults::proxy_shard_cache_key_include_version",
+        deserialize_with = "env_var::bool"
+    )]
+    pub include_version: bool,
+
+    #[serde(
+        default = "defaults::proxy_shard_cache_key_include_origin",
+        deserialize_with = "env_var::bool"
+    )]
+    pub include_origin: bool,
+
+    #[serde(default)]
+    pub include_headers: Vec<String>,
+
+    #[serde(default)]
+    pub include_query: Vec<String>,
+
+    #[serde(default)]
+    pub exclude_query: Vec<String>,
+
+    #[serde(default, deserialize_with = "env_var::vec_regex")]
+    pub exclude_query_patterns: Vec<Regex>,
+
+    #[serde(
+        default = "defaults::proxy_shard_cache_key_sort_query",
+        deserialize_with = "env_var::bool"
+    )]
+    pub sort_query: bool,
+
+    #[serde(
+        default = "defaults::proxy_shard_cache_key_normalize_query",
+        deserialize_with = "env_var::bool"
+    )]
+    pub normalize_query: bool,
+
+    #[serde(
+        default = "defaults::proxy_shard_cache_key_forward_query",
+        deserialize_with = "env_var::bool"
+    )]
+    pub forward_query: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProxyShardIdentity {
+    pub source: ConfigProxyShardIdentitySource,
+
+    #[serde(deserialize_with = "env_var::str")]
+    pub name: String,
+
+    #[serde(
+        default = "defaults::proxy_shard_identity_trim_scheme",
+        deserialize_with = "env_var::bool"
+    )]
+    pub trim_scheme: bool,
+
+    #[serde(
+        default = "defaults::proxy_shard_identity_lowercase",
+        deserialize_with = "env_var::bool"
+    )]
+    pub lowercase: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ConfigProxyShardIdentitySource {
+    #[serde(rename = "header")]
+    Header,
+
+    #[serde(rename = "cookie")]
+    Cookie,
+
+    #[serde(rename = "query")]
+    Query,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProxyShardJwt {
+    #[serde(deserialize_with = "env_var::str")]
+    pub claim: String,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub secret: Option<String>,
+
+    pub jwks: Option<PathBuf>,
+
+    #[serde(
+        default = "defaults::proxy_shard_jwt_insecure_skip_verify",
+        deserialize_with = "env_var::bool"
+    )]
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProxyShardTls {
+    pub ca_bundle: Option<PathBuf>,
+    pub client_certificate: Option<PathBuf>,
+    pub client_private_key: Option<PathBuf>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub server_name: Option<String>,
+
+    #[serde(
+        default = "defaults::proxy_shard_tls_insecure_skip_verify",
+        deserialize_with = "env_var::bool"
+    )]
+    pub insecure_skip_verify: bool,
+}
+
+impl fmt::Display for ConfigInet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigInet::Tcp(ref addr) => write!(f, "{}", addr),
+            ConfigInet::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl ConfigProxyShardScheme {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ConfigProxyShardScheme::Http => "http",
+            ConfigProxyShardScheme::Https => "https",
+        }
+    }
+}
+
+impl ConfigProxy {
+    pub fn find_shard(&self, shard: u8) -> Option<&ConfigProxyShard> {
+        self.shard
+            .iter()
+            .find(|shard_config| shard_config.shard == shard)
+    }
 }
 
 #[derive(Deserialize)]
@@ -84,6 +349,12 @@ pub struct ConfigCache {
     #[serde(default = "defaults::cache_ttl_default")]
     pub ttl_default: usize,
 
+    #[serde(default = "defaults::cache_grace_default")]
+    pub grace_default: usize,
+
+    #[serde(default = "defaults::cache_revalidate_default")]
+    pub revalidate_default: usize,
+
     #[serde(
         default = "defaults::cache_disable_read",
         deserialize_with = "env_var::bool"
@@ -107,6 +378,35 @@ pub struct ConfigCache {
 
     #[serde(default = "defaults::cache_compress_above_bytes")]
     pub compress_above_bytes: usize,
+
+    #[serde(default = "defaults::cache_hasher")]
+    pub hasher: ConfigCacheHasher,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ConfigCacheHasher {
+    #[serde(rename = "farmhash32")]
+    FarmHash32,
+
+    #[serde(rename = "farmhash64")]
+    FarmHash64,
+
+    #[serde(rename = "xxh3-64")]
+    Xxh3_64,
+
+    #[serde(rename = "xxh3-128")]
+    Xxh3_128,
+}
+
+impl ConfigCacheHasher {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ConfigCacheHasher::FarmHash32 => "farmhash32",
+            ConfigCacheHasher::FarmHash64 => "farmhash64",
+            ConfigCacheHasher::Xxh3_64 => "xxh3-64",
+            ConfigCacheHasher::Xxh3_128 => "xxh3-128",
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -4,18 +4,37 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::net::SocketAddr;
+use super::config::{
+    ConfigCacheHasher, ConfigInet, ConfigProxyShardBalance, ConfigProxyShardCacheKey,
+    ConfigProxyShardScheme,
+};
 
 pub fn server_log_level() -> String {
     "error".to_string()
 }
 
-pub fn server_inet() -> SocketAddr {
-    "[::1]:8080".parse().unwrap()
+pub fn server_inet() -> ConfigInet {
+    ConfigInet::Tcp("[::1]:8080".parse().unwrap())
 }
 
-pub fn control_inet() -> SocketAddr {
-    "[::1]:8811".parse().unwrap()
+pub fn server_tls_handshake_timeout_seconds() -> u64 {
+    10
+}
+
+pub fn server_http2_max_concurrent_streams() -> u32 {
+    200
+}
+
+pub fn server_http2_initial_stream_window_size() -> u32 {
+    1048576
+}
+
+pub fn server_http2_initial_connection_window_size() -> u32 {
+    1048576
+}
+
+pub fn control_inet() -> ConfigInet {
+    ConfigInet::Tcp("[::1]:8811".parse().unwrap())
 }
 
 pub fn control_tcp_timeout() -> u64 {
@@ -30,6 +49,10 @@ pub fn proxy_shard_shard() -> u8 {
     0
 }
 
+pub fn proxy_shard_scheme() -> ConfigProxyShardScheme {
+    ConfigProxyShardScheme::Http
+}
+
 pub fn proxy_shard_host() -> String {
     "localhost".to_string()
 }
@@ -38,14 +61,104 @@ pub fn proxy_shard_port() -> u16 {
     3000
 }
 
+pub fn proxy_shard_balance() -> ConfigProxyShardBalance {
+    ConfigProxyShardBalance::RoundRobin
+}
+
+pub fn proxy_shard_backend_weight() -> u32 {
+    1
+}
+
+pub fn proxy_shard_honor_cache_control() -> bool {
+    false
+}
+
+pub fn proxy_shard_honor_request_cache_control() -> bool {
+    false
+}
+
+pub fn proxy_shard_cache_key() -> ConfigProxyShardCacheKey {
+    ConfigProxyShardCacheKey {
+        include_version: proxy_shard_cache_key_include_version(),
+        include_origin: proxy_shard_cache_key_include_origin(),
+        include_headers: Vec::new(),
+        include_query: Vec::new(),
+        exclude_query: Vec::new(),
+        exclude_query_patterns: Vec::new(),
+        sort_query: proxy_shard_cache_key_sort_query(),
+        normalize_query: proxy_shard_cache_key_normalize_query(),
+        forward_query: proxy_shard_cache_key_forward_query(),
+    }
+}
+
+pub fn proxy_shard_cache_key_include_version() -> bool {
+    true
+}
+
+pub fn proxy_shard_cache_key_include_origin() -> bool {
+    true
+}
+
+pub fn proxy_shard_cache_key_sort_query() -> bool {
+    false
+}
+
+pub fn proxy_shard_cache_key_normalize_query() -> bool {
+    false
+}
+
+pub fn proxy_shard_cache_key_forward_query() -> bool {
+    false
+}
+
+pub fn proxy_shard_identity_trim_scheme() -> bool {
+    false
+}
+
+pub fn proxy_shard_identity_lowercase() -> bool {
+    false
+}
+
+pub fn proxy_shard_jwt_insecure_skip_verify() -> bool {
+    false
+}
+
+pub fn proxy_shard_tls_insecure_skip_verify() -> bool {
+    false
+}
+
 pub fn proxy_lock_tunnel_path() -> bool {
     false
 }
 
+pub fn proxy_max_headers() -> usize {
+    100
+}
+
+pub fn proxy_compress_responses() -> bool {
+    false
+}
+
+pub fn proxy_compress_responses_above_bytes() -> usize {
+    1024
+}
+
+pub fn proxy_tunnel_head_as_get() -> bool {
+    false
+}
+
 pub fn cache_ttl_default() -> usize {
     600
 }
 
+pub fn cache_grace_default() -> usize {
+    0
+}
+
+pub fn cache_revalidate_default() -> usize {
+    0
+}
+
 pub fn cache_disable_read() -> bool {
     false
 }
@@ -66,6 +179,10 @@ pub fn cache_compress_above_bytes() -> usize {
     1024
 }
 
+pub fn cache_hasher() -> ConfigCacheHasher {
+    ConfigCacheHasher::FarmHash32
+}
+
 pub fn redis_host() -> String {
     "localhost".to_string()
 }
@@ -4,12 +4,14 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::net::SocketAddr;
+use std::path::PathBuf;
 
 use regex::Regex;
 use serde::{de, Deserialize, Deserializer};
 use toml::Value;
 
+use super::config::ConfigInet;
+
 #[derive(Deserialize, PartialEq)]
 struct WrappedString(String);
 
@@ -41,15 +43,15 @@ where
     })
 }
 
-pub fn socket_addr<'de, D>(deserializer: D) -> Result<SocketAddr, D::Error>
+pub fn inet<'de, D>(deserializer: D) -> Result<ConfigInet, D::Error>
 where
     D: Deserializer<'de>,
 {
     let value = String::deserialize(deserializer)?;
 
     match is_env_var(&value) {
-        true => Ok(get_env_var_str(&value).parse().unwrap()),
-        false => Ok(value.parse().unwrap()),
+        true => Ok(make_inet(&get_env_var_str(&value))),
+        false => Ok(make_inet(&value)),
     }
 }
 
@@ -67,6 +69,32 @@ where
     })
 }
 
+pub fn vec_regex<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|value| {
+            let value = match is_env_var(&value) {
+                true => get_env_var_str(&value),
+                false => value,
+            };
+
+            Regex::new(&value).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+fn make_inet(value: &str) -> ConfigInet {
+    // Notice: UNIX socket paths are prefixed with 'unix:' (eg. \
+    //   'unix:/run/bloom.sock'), as done in NGINX configurations.
+    match value.strip_prefix("unix:") {
+        Some(path) => ConfigInet::Unix(PathBuf::from(path)),
+        None => ConfigInet::Tcp(value.parse().unwrap()),
+    }
+}
+
 fn is_env_var(value: &str) -> bool {
     Regex::new(r"^\$\{[A-Z_0-9]+\}$")
         .expect("env_var: regex is invalid")
@@ -118,6 +146,18 @@ mod tests {
         assert!(!is_env_var("${ÉÍÁ}"));
     }
 
+    #[test]
+    fn it_makes_inet() {
+        assert_eq!(
+            make_inet("[::1]:8080"),
+            ConfigInet::Tcp("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(
+            make_inet("unix:/run/bloom.sock"),
+            ConfigInet::Unix(PathBuf::from("/run/bloom.sock"))
+        );
+    }
+
     #[test]
     fn it_gets_string_value() {
         std::env::set_var("TEST", "test");
@@ -4,8 +4,9 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::io::{ErrorKind, Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
 use std::result::Result;
 use std::str;
 use std::time::Duration;
@@ -17,12 +18,16 @@ use super::command::ControlCommand;
 use super::command::ControlCommandResponse;
 use super::command::COMMAND_SIZE;
 use crate::cache::route::CacheRoute;
-use crate::cache::route::ROUTE_HASH_SIZE;
+use crate::config::config::ConfigCacheHasher;
 use crate::APP_CONF;
 use crate::LINE_FEED;
 
 pub struct ControlHandle;
 
+pub trait ControlHandleStream: Read + Write {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()>;
+}
+
 enum ControlHandleError {
     Closed,
     IncompatibleHasher,
@@ -40,9 +45,7 @@ enum ControlHandleMessageResult {
 }
 
 const LINE_END_GAP: usize = 1;
-const MAX_LINE_SIZE: usize = COMMAND_SIZE + ROUTE_HASH_SIZE + LINE_END_GAP + 1;
 const HASH_VALUE_SIZE: usize = 10;
-const HASH_RESULT_SIZE: usize = 7 + ROUTE_HASH_SIZE + LINE_END_GAP + 1;
 const SHARD_INITIAL: ControlShard = 0;
 const TCP_TIMEOUT_NON_ESTABLISHED: u64 = 20;
 
@@ -56,6 +59,12 @@ lazy_static! {
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION")
     );
+
+    // Notice: line sizes depend on the size of hashes, which depends on the \
+    //   configured hasher.
+    static ref MAX_LINE_SIZE: usize =
+        COMMAND_SIZE + CacheRoute::hash_size() + LINE_END_GAP + 1;
+    static ref HASH_RESULT_SIZE: usize = 7 + CacheRoute::hash_size() + LINE_END_GAP + 1;
 }
 
 impl ControlHandleError {
@@ -72,8 +81,23 @@ impl ControlHandleError {
     }
 }
 
+impl ControlHandleStream for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_nodelay(true)?;
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+impl ControlHandleStream for UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
 impl ControlHandle {
-    pub fn client(mut stream: TcpStream) {
+    pub fn client<S: ControlHandleStream>(mut stream: S) {
         // Configure stream (non-established)
         ControlHandle::configure_stream(&stream, false);
 
@@ -81,7 +105,7 @@ impl ControlHandle {
         write!(stream, "{}{}", *CONNECTED_BANNER, LINE_FEED).expect("write failed");
 
         // Ensure client hasher is compatible
-        match Self::ensure_hasher(&stream) {
+        match Self::ensure_hasher(&mut stream) {
             Ok(_) => {
                 // Configure stream (established)
                 ControlHandle::configure_stream(&stream, true);
@@ -97,7 +121,7 @@ impl ControlHandle {
 
                 // Wait for incoming messages
                 'handler: loop {
-                    let mut read = [0; MAX_LINE_SIZE];
+                    let mut read = vec![0; *MAX_LINE_SIZE];
 
                     match stream.read(&mut read) {
                         Ok(n) => {
@@ -118,7 +142,7 @@ impl ControlHandle {
 
                                     for line in buffer_split {
                                         if line.is_empty() == false {
-                                            if Self::on_message(&mut shard, &stream, line)
+                                            if Self::on_message(&mut shard, &mut stream, line)
                                                 == ControlHandleMessageResult::Close
                                             {
                                                 // Should close?
@@ -146,40 +170,53 @@ impl ControlHandle {
         }
     }
 
-    fn configure_stream(stream: &TcpStream, is_established: bool) {
+    fn configure_stream<S: ControlHandleStream>(stream: &S, is_established: bool) {
         let tcp_timeout = if is_established == true {
             APP_CONF.control.tcp_timeout
         } else {
             TCP_TIMEOUT_NON_ESTABLISHED
         };
 
-        assert!(stream.set_nodelay(true).is_ok());
-
-        assert!(stream
-            .set_read_timeout(Some(Duration::new(tcp_timeout, 0)))
-            .is_ok());
-        assert!(stream
-            .set_write_timeout(Some(Duration::new(tcp_timeout, 0)))
-            .is_ok());
+        assert!(stream.set_timeouts(Duration::new(tcp_timeout, 0)).is_ok());
     }
 
-    fn ensure_hasher(mut stream: &TcpStream) -> Result<Option<()>, ControlHandleError> {
+    fn ensure_hasher<S: ControlHandleStream>(
+        stream: &mut S,
+    ) -> Result<Option<()>, ControlHandleError> {
         let test_value: String = rand::rng()
             .sample_iter(&Alphanumeric)
             .take(HASH_VALUE_SIZE)
             .map(char::from)
             .collect();
         let test_hash = CacheRoute::hash(test_value.as_str());
-
-        write!(stream, "HASHREQ {}{}", test_value, LINE_FEED).expect("write failed");
+        let test_hasher = CacheRoute::hasher();
+
+        // Advertise hasher algorithm along with the test value, unless it is \
+        //   the legacy one (which clients expect to be used if none is \
+        //   advertised). Clients that are unaware of the advertised \
+        //   algorithm respond with a mismatching hash, and thus fail early.
+        if test_hasher == ConfigCacheHasher::FarmHash32 {
+            write!(stream, "HASHREQ {}{}", test_value, LINE_FEED).expect("write failed");
+        } else {
+            write!(
+                stream,
+                "HASHREQ {} {}{}",
+                test_value,
+                test_hasher.to_str(),
+                LINE_FEED
+            )
+            .expect("write failed");
+        }
 
         debug!(
-            "sent hasher request: {} and expecting hash: {}",
-            test_value, test_hash
+            "sent hasher request: {} with hasher: {} and expecting hash: {}",
+            test_value,
+            test_hasher.to_str(),
+            test_hash
         );
 
         loop {
-            let mut read = [0; HASH_RESULT_SIZE];
+            let mut read = vec![0; *HASH_RESULT_SIZE];
 
             match stream.read(&mut read) {
                 Ok(n) => {
@@ -221,9 +258,9 @@ impl ControlHandle {
         }
     }
 
-    fn on_message(
+    fn on_message<S: ControlHandleStream>(
         shard: &mut ControlShard,
-        mut stream: &TcpStream,
+        stream: &mut S,
         message_slice: &[u8],
     ) -> ControlHandleMessageResult {
         let message = str::from_utf8(message_slice).unwrap_or("");
@@ -4,13 +4,17 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::net::TcpListener;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixListener;
 use std::process;
 use std::thread;
 
 use tokio::runtime::Runtime;
 
-use super::handle::ControlHandle;
+use super::handle::{ControlHandle, ControlHandleStream};
+use crate::config::config::ConfigInet;
+use crate::server::socket::ServerSocket;
 use crate::{APP_CONF, THREAD_NAME_CONTROL_CLIENT, THREAD_NAME_CONTROL_MASTER};
 
 lazy_static! {
@@ -33,40 +37,68 @@ impl ControlListen {
     pub fn run(&self) {
         thread::Builder::new()
             .name(THREAD_NAME_CONTROL_MASTER.to_string())
-            .spawn(move || {
-                match TcpListener::bind(APP_CONF.control.inet) {
+            .spawn(move || match APP_CONF.control.inet {
+                ConfigInet::Tcp(addr) => match TcpListener::bind(addr) {
                     Ok(listener) => {
-                        info!("listening on tcp://{}", APP_CONF.control.inet);
+                        info!("listening on tcp://{}", addr);
 
-                        for stream in listener.incoming() {
-                            match stream {
-                                Ok(stream) => {
-                                    thread::Builder::new()
-                                        .name(THREAD_NAME_CONTROL_CLIENT.to_string())
-                                        .spawn(move || {
-                                            if let Ok(peer_addr) = stream.peer_addr() {
-                                                debug!("control client connecting: {}", peer_addr);
-                                            }
-
-                                            // Create client
-                                            ControlHandle::client(stream);
-                                        })
-                                        .ok();
-                                }
-                                Err(err) => {
-                                    warn!("error handling stream: {}", err);
-                                }
+                        Self::accept(listener.incoming().inspect(|stream| {
+                            if let Ok(Ok(peer_addr)) = stream.as_ref().map(TcpStream::peer_addr) {
+                                debug!("control client connecting: {}", peer_addr);
                             }
-                        }
+                        }));
                     }
-                    Err(err) => {
-                        error!("error binding control listener: {}", err);
+                    Err(err) => Self::exit(err),
+                },
+                ConfigInet::Unix(ref path) => {
+                    ServerSocket::prepare(path);
+
+                    match UnixListener::bind(path) {
+                        Ok(listener) => {
+                            ServerSocket::secure(
+                                path,
+                                APP_CONF.control.socket_permissions,
+                                APP_CONF.control.socket_owner.as_deref(),
+                            );
 
-                        // Exit Bloom
-                        process::exit(1);
+                            info!("listening on unix:{}", path.display());
+
+                            Self::accept(listener.incoming());
+                        }
+                        Err(err) => Self::exit(err),
                     }
                 }
             })
             .ok();
     }
+
+    fn accept<S, I>(incoming: I)
+    where
+        S: ControlHandleStream + Send + 'static,
+        I: Iterator<Item = io::Result<S>>,
+    {
+        for stream in incoming {
+            match stream {
+                Ok(stream) => {
+                    thread::Builder::new()
+                        .name(THREAD_NAME_CONTROL_CLIENT.to_string())
+                        .spawn(move || {
+                            // Create client
+                            ControlHandle::client(stream);
+                        })
+                        .ok();
+                }
+                Err(err) => {
+                    warn!("error handling stream: {}", err);
+                }
+            }
+        }
+    }
+
+    fn exit(err: io::Error) {
+        error!("error binding control listener: {}", err);
+
+        // Exit Bloom
+        process::exit(1);
+    }
 }
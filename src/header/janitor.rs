@@ -7,13 +7,25 @@
 use hyper::header::{self, HeaderMap, HeaderName};
 
 use super::response_buckets::HeaderResponseBloomResponseBuckets;
+use super::response_grace::HeaderResponseBloomResponseGrace;
 use super::response_ignore::HeaderResponseBloomResponseIgnore;
+use super::response_revalidate::HeaderResponseBloomResponseRevalidate;
+use super::response_scope::HeaderResponseBloomResponseScope;
 use super::response_ttl::HeaderResponseBloomResponseTTL;
+use super::response_vary::HeaderResponseBloomResponseVary;
+use crate::APP_CONF;
 
 pub struct HeaderJanitor;
 
 impl HeaderJanitor {
     pub fn clean(headers: &mut HeaderMap) {
+        Self::clean_with(headers, &APP_CONF.proxy.strip_headers)
+    }
+
+    pub fn clean_with(headers: &mut HeaderMap, strip_extra: &[String]) {
+        // Strip hop-by-hop headers first, as they may reference other headers
+        Self::strip_hop_by_hop(headers, strip_extra);
+
         // Collect header names that should be removed
         let headers_remove: Vec<HeaderName> = headers
             .keys()
@@ -27,16 +39,108 @@ impl HeaderJanitor {
         }
     }
 
+    pub fn clean_request(headers: &mut HeaderMap) {
+        // Only strip hop-by-hop headers from requests, as contextual headers \
+        //   (eg. 'Cookie') are meant to be forwarded to the API
+        Self::strip_hop_by_hop(headers, &APP_CONF.proxy.strip_headers);
+    }
+
     pub fn is_contextual(name: &HeaderName) -> bool {
+        name == header::DATE || name == header::COOKIE
+    }
+
+    pub fn is_hop_by_hop(name: &HeaderName) -> bool {
+        // Notice: 'Proxy-Connection' is not part of RFC 7230, though it is \
+        //   still sent by some legacy clients.
         name == header::CONNECTION
-            || name == header::DATE
+            || name == header::PROXY_AUTHENTICATE
+            || name == header::PROXY_AUTHORIZATION
+            || name == header::TE
+            || name == header::TRAILER
+            || name == header::TRANSFER_ENCODING
             || name == header::UPGRADE
-            || name == header::COOKIE
+            || name.as_str() == "keep-alive"
+            || name.as_str() == "proxy-connection"
     }
 
     pub fn is_internal(name: &HeaderName) -> bool {
         name.as_str() == HeaderResponseBloomResponseBuckets::header_name().as_str()
             || name.as_str() == HeaderResponseBloomResponseIgnore::header_name().as_str()
             || name.as_str() == HeaderResponseBloomResponseTTL::header_name().as_str()
+            || name.as_str() == HeaderResponseBloomResponseGrace::header_name().as_str()
+            || name.as_str() == HeaderResponseBloomResponseRevalidate::header_name().as_str()
+            || name.as_str() == HeaderResponseBloomResponseVary::header_name().as_str()
+            || name.as_str() == HeaderResponseBloomResponseScope::header_name().as_str()
+    }
+
+    fn strip_hop_by_hop(headers: &mut HeaderMap, strip_extra: &[String]) {
+        // Collect header names that should be removed, including those \
+        //   listed in the 'Connection' header (RFC 7230, section 6.1)
+        let mut headers_remove: Vec<HeaderName> = headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .collect();
+
+        headers_remove.extend(
+            headers
+                .keys()
+                .filter(|name| {
+                    Self::is_hop_by_hop(name)
+                        || strip_extra
+                            .iter()
+                            .any(|extra| name.as_str().eq_ignore_ascii_case(extra))
+                })
+                .cloned(),
+        );
+
+        // Proceed removal (on original headers object)
+        for header_remove in headers_remove {
+            headers.remove(&header_remove);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn it_strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        headers.insert(header::TRAILER, HeaderValue::from_static("Expires"));
+        headers.insert(
+            header::PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic"),
+        );
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-private", HeaderValue::from_static("1"));
+        headers.insert("x-powered-by", HeaderValue::from_static("Express"));
+        headers.append(header::CONNECTION, HeaderValue::from_static("keep-alive"));
+        headers.append(
+            header::CONNECTION,
+            HeaderValue::from_static("X-Private, Upgrade"),
+        );
+
+        HeaderJanitor::strip_hop_by_hop(&mut headers, &["X-Powered-By".to_string()]);
+
+        assert_eq!(
+            headers
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["content-type"]
+        );
     }
 }
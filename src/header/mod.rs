@@ -7,6 +7,10 @@
 pub mod janitor;
 pub mod request_shard;
 pub mod response_buckets;
+pub mod response_grace;
 pub mod response_ignore;
+pub mod response_revalidate;
+pub mod response_scope;
 pub mod response_ttl;
+pub mod response_vary;
 pub mod status;
@@ -0,0 +1,32 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::fmt;
+
+use hyper::header::{HeaderName, HeaderValue};
+
+#[derive(Clone)]
+pub struct HeaderResponseBloomResponseGrace(pub usize);
+
+impl HeaderResponseBloomResponseGrace {
+    pub fn header_name() -> HeaderName {
+        HeaderName::from_static("bloom-response-grace")
+    }
+
+    pub fn from_header_value(value: &HeaderValue) -> Option<Self> {
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .map(HeaderResponseBloomResponseGrace)
+    }
+}
+
+impl fmt::Display for HeaderResponseBloomResponseGrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
@@ -0,0 +1,84 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::fmt;
+
+use hyper::header::{HeaderName, HeaderValue};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderResponseBloomResponseScopeValue {
+    Public,
+    Private,
+}
+
+#[derive(Clone)]
+pub struct HeaderResponseBloomResponseScope(pub HeaderResponseBloomResponseScopeValue);
+
+impl HeaderResponseBloomResponseScopeValue {
+    fn to_str(self) -> &'static str {
+        match self {
+            HeaderResponseBloomResponseScopeValue::Public => "public",
+            HeaderResponseBloomResponseScopeValue::Private => "private",
+        }
+    }
+}
+
+impl HeaderResponseBloomResponseScope {
+    pub fn header_name() -> HeaderName {
+        HeaderName::from_static("bloom-response-scope")
+    }
+
+    pub fn from_header_value(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?.trim();
+
+        if value.eq_ignore_ascii_case("public") == true {
+            Some(HeaderResponseBloomResponseScope(
+                HeaderResponseBloomResponseScopeValue::Public,
+            ))
+        } else if value.eq_ignore_ascii_case("private") == true {
+            Some(HeaderResponseBloomResponseScope(
+                HeaderResponseBloomResponseScopeValue::Private,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for HeaderResponseBloomResponseScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.0.to_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_scope_value() {
+        assert_eq!(
+            HeaderResponseBloomResponseScope::from_header_value(&HeaderValue::from_static(
+                "public"
+            ))
+            .map(|scope| scope.0),
+            Some(HeaderResponseBloomResponseScopeValue::Public)
+        );
+        assert_eq!(
+            HeaderResponseBloomResponseScope::from_header_value(&HeaderValue::from_static(
+                " Private"
+            ))
+            .map(|scope| scope.0),
+            Some(HeaderResponseBloomResponseScopeValue::Private)
+        );
+        assert!(
+            HeaderResponseBloomResponseScope::from_header_value(&HeaderValue::from_static(
+                "shared"
+            ))
+            .is_none()
+        );
+    }
+}
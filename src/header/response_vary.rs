@@ -0,0 +1,38 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::fmt;
+
+use hyper::header::{HeaderName, HeaderValue};
+
+#[derive(Clone)]
+pub struct HeaderResponseBloomResponseVary(pub Vec<String>);
+
+impl HeaderResponseBloomResponseVary {
+    pub fn header_name() -> HeaderName {
+        HeaderName::from_static("bloom-response-vary")
+    }
+
+    pub fn from_header_value(value: &HeaderValue) -> Option<Self> {
+        value
+            .to_str()
+            .ok()
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .map(HeaderResponseBloomResponseVary)
+    }
+}
+
+impl fmt::Display for HeaderResponseBloomResponseVary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.join(", "))
+    }
+}
@@ -15,6 +15,8 @@ pub enum HeaderBloomStatusValue {
     Direct,
     Reject,
     Offline,
+    Stale,
+    StaleRevalidate,
 }
 
 #[derive(Clone)]
@@ -28,6 +30,8 @@ impl HeaderBloomStatusValue {
             HeaderBloomStatusValue::Direct => "DIRECT",
             HeaderBloomStatusValue::Reject => "REJECT",
             HeaderBloomStatusValue::Offline => "OFFLINE",
+            HeaderBloomStatusValue::Stale => "STALE",
+            HeaderBloomStatusValue::StaleRevalidate => "STALE-REVALIDATE",
         }
     }
 }
@@ -59,5 +63,10 @@ mod tests {
         assert_eq!(HeaderBloomStatusValue::Direct.to_str(), "DIRECT");
         assert_eq!(HeaderBloomStatusValue::Reject.to_str(), "REJECT");
         assert_eq!(HeaderBloomStatusValue::Offline.to_str(), "OFFLINE");
+        assert_eq!(HeaderBloomStatusValue::Stale.to_str(), "STALE");
+        assert_eq!(
+            HeaderBloomStatusValue::StaleRevalidate.to_str(),
+            "STALE-REVALIDATE"
+        );
     }
 }
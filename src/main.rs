@@ -11,7 +11,6 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 extern crate farmhash;
-extern crate httparse;
 extern crate hyper;
 extern crate rand;
 extern crate redis;
@@ -35,12 +34,14 @@ use std::time::Duration;
 use clap::{Arg, Command};
 use log::LevelFilter;
 
+use cache::route::CacheRoute;
 use cache::store::{CacheStore, CacheStoreBuilder};
 use config::config::Config;
 use config::logger::ConfigLogger;
 use config::reader::ConfigReader;
 use control::listen::ControlListenBuilder;
 use proxy::logger::{ProxyLogger, ProxyLoggerBuilder};
+use proxy::token::{ProxyTokenBuilder, ProxyTokenKeyrings};
 use server::listen::ServerListenBuilder;
 
 struct AppArgs {
@@ -59,6 +60,7 @@ lazy_static! {
     static ref APP_CONF: Config = ConfigReader::make();
     static ref APP_CACHE_STORE: CacheStore = CacheStoreBuilder::new();
     static ref APP_PROXY_LOGGER: Option<ProxyLogger> = ProxyLoggerBuilder::new();
+    static ref APP_PROXY_TOKEN_KEYRINGS: ProxyTokenKeyrings = ProxyTokenBuilder::new();
 }
 
 fn make_app_args() -> AppArgs {
@@ -86,11 +88,12 @@ fn make_app_args() -> AppArgs {
 
 fn ensure_states() {
     // Ensure all statics are valid (a `deref` is enough to lazily initialize them)
-    let (_, _, _, _) = (
+    let (_, _, _, _, _) = (
         APP_ARGS.deref(),
         APP_CONF.deref(),
         APP_CACHE_STORE.deref(),
         APP_PROXY_LOGGER.deref(),
+        APP_PROXY_TOKEN_KEYRINGS.deref(),
     );
 }
 
@@ -124,6 +127,9 @@ fn main() {
 
     info!("starting up");
 
+    // Bind route hasher (cache keys and control hashes depend on it)
+    CacheRoute::bind_hasher(APP_CONF.cache.hasher);
+
     // Ensure all states are bound
     ensure_states();
 
@@ -0,0 +1,300 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::{self, RngExt};
+use xxhash_rust::xxh3;
+
+use crate::config::config::ConfigProxyShardBalance;
+
+const RING_POINTS_PER_WEIGHT: u32 = 40;
+
+pub struct ProxyBalance {
+    policy: ConfigProxyShardBalance,
+    weights: Vec<u32>,
+    schedule: Vec<usize>,
+    ring: Vec<(u64, usize)>,
+    counter: AtomicUsize,
+    in_flight: Vec<AtomicUsize>,
+}
+
+pub struct ProxyBalanceGuard<'a> {
+    in_flight: &'a AtomicUsize,
+}
+
+impl ProxyBalance {
+    pub fn new(policy: ConfigProxyShardBalance, backends: &[(String, u32)]) -> Self {
+        let weights: Vec<u32> = backends.iter().map(|(_, weight)| *weight).collect();
+
+        ProxyBalance {
+            policy,
+            schedule: Self::make_schedule(&weights),
+            ring: if policy == ConfigProxyShardBalance::ConsistentHash {
+                Self::make_ring(backends)
+            } else {
+                Vec::new()
+            },
+            counter: AtomicUsize::new(0),
+            in_flight: weights.iter().map(|_| AtomicUsize::new(0)).collect(),
+            weights,
+        }
+    }
+
+    pub fn pick(&self, key: &str) -> usize {
+        // Single backend? (no choice to be made)
+        if self.weights.len() <= 1 {
+            return 0;
+        }
+
+        match self.policy {
+            ConfigProxyShardBalance::RoundRobin => {
+                self.schedule[self.counter.fetch_add(1, Ordering::Relaxed) % self.schedule.len()]
+            }
+            ConfigProxyShardBalance::LeastInFlight => self.pick_least_in_flight(),
+            ConfigProxyShardBalance::RandomTwoChoices => self.pick_random_two_choices(),
+            ConfigProxyShardBalance::ConsistentHash => self.pick_consistent_hash(key),
+        }
+    }
+
+    pub fn acquire(&self, backend: usize) -> ProxyBalanceGuard<'_> {
+        let in_flight = &self.in_flight[backend];
+
+        in_flight.fetch_add(1, Ordering::Relaxed);
+
+        ProxyBalanceGuard { in_flight }
+    }
+
+    fn pick_least_in_flight(&self) -> usize {
+        // Notice: start from a rotating offset, so that ties do not always \
+        //   resolve to the first backends.
+        let offset = self.counter.fetch_add(1, Ordering::Relaxed);
+        let count = self.weights.len();
+
+        let mut best = offset % count;
+
+        for step in 1..count {
+            let backend = (offset + step) % count;
+
+            if self.is_less_loaded(backend, best) == true {
+                best = backend;
+            }
+        }
+
+        best
+    }
+
+    fn pick_random_two_choices(&self) -> usize {
+        // Draw both choices from the weighted schedule, so that heavier \
+        //   backends are drawn more often
+        let mut rng = rand::rng();
+
+        let first = self.schedule[rng.random_range(0..self.schedule.len())];
+        let second = self.schedule[rng.random_range(0..self.schedule.len())];
+
+        if self.is_less_loaded(second, first) == true {
+            second
+        } else {
+            first
+        }
+    }
+
+    fn pick_consistent_hash(&self, key: &str) -> usize {
+        let hash = xxh3::xxh3_64(key.as_bytes());
+
+        // Pick first point clockwise on the ring (wrapping around)
+        let index = self.ring.partition_point(|(point, _)| *point < hash);
+
+        self.ring[index % self.ring.len()].1
+    }
+
+    fn is_less_loaded(&self, backend: usize, other: usize) -> bool {
+        // Compare in-flight requests relative to weights (cross-multiplied \
+        //   to avoid divisions)
+        let load = self.in_flight[backend].load(Ordering::Relaxed) as u64;
+        let other_load = self.in_flight[other].load(Ordering::Relaxed) as u64;
+
+        load * (self.weights[other] as u64) < other_load * (self.weights[backend] as u64)
+    }
+
+    fn make_schedule(weights: &[u32]) -> Vec<usize> {
+        // Build a smooth weighted round-robin sequence (as NGINX does), so \
+        //   that heavier backends do not get consecutive requests in bursts
+        let divisor = weights
+            .iter()
+            .fold(0, |divisor, weight| gcd(divisor, *weight));
+
+        if divisor == 0 {
+            return vec![0];
+        }
+
+        let weights: Vec<i64> = weights
+            .iter()
+            .map(|weight| (weight / divisor) as i64)
+            .collect();
+        let total: i64 = weights.iter().sum();
+
+        let mut current = vec![0; weights.len()];
+        let mut schedule = Vec::with_capacity(total as usize);
+
+        for _ in 0..total {
+            let mut best = 0;
+
+            for (backend, weight) in weights.iter().enumerate() {
+                current[backend] += weight;
+
+                if current[backend] > current[best] {
+                    best = backend;
+                }
+            }
+
+            current[best] -= total;
+
+            schedule.push(best);
+        }
+
+        schedule
+    }
+
+    fn make_ring(backends: &[(String, u32)]) -> Vec<(u64, usize)> {
+        // Notice: ring points are derived from backend identities (not \
+        //   positions), so that adding or removing a backend only moves the \
+        //   keys that were mapped to it.
+        let mut ring = Vec::new();
+
+        for (backend, (identity, weight)) in backends.iter().enumerate() {
+            for point in 0..(weight * RING_POINTS_PER_WEIGHT) {
+                ring.push((
+                    xxh3::xxh3_64(format!("{}#{}", identity, point).as_bytes()),
+                    backend,
+                ));
+            }
+        }
+
+        ring.sort_unstable();
+
+        ring
+    }
+}
+
+impl<'a> Drop for ProxyBalanceGuard<'a> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_backends(weights: &[u32]) -> Vec<(String, u32)> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(index, weight)| (format!("backend-{}:3000", index), *weight))
+            .collect()
+    }
+
+    #[test]
+    fn it_makes_smooth_schedule() {
+        assert_eq!(ProxyBalance::make_schedule(&[1, 1]), vec![0, 1]);
+        assert_eq!(
+            ProxyBalance::make_schedule(&[5, 1, 1]),
+            vec![0, 0, 1, 0, 2, 0, 0]
+        );
+        assert_eq!(ProxyBalance::make_schedule(&[20, 10]), vec![0, 1, 0]);
+    }
+
+    #[test]
+    fn it_picks_round_robin() {
+        let balance =
+            ProxyBalance::new(ConfigProxyShardBalance::RoundRobin, &make_backends(&[2, 1]));
+
+        let picks: Vec<usize> = (0..6).map(|_| balance.pick("")).collect();
+
+        assert_eq!(picks, vec![0, 1, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn it_picks_least_in_flight() {
+        let balance = ProxyBalance::new(
+            ConfigProxyShardBalance::LeastInFlight,
+            &make_backends(&[1, 1, 2]),
+        );
+
+        let _first = balance.acquire(0);
+        let _second = balance.acquire(1);
+
+        assert_eq!(balance.pick(""), 2);
+
+        let _third = balance.acquire(2);
+
+        assert_eq!(balance.pick(""), 2);
+
+        {
+            let _fourth = balance.acquire(2);
+            let _fifth = balance.acquire(2);
+
+            assert_ne!(balance.pick(""), 2);
+        }
+
+        assert_eq!(balance.pick(""), 2);
+    }
+
+    #[test]
+    fn it_picks_random_two_choices() {
+        let balance = ProxyBalance::new(
+            ConfigProxyShardBalance::RandomTwoChoices,
+            &make_backends(&[1, 1]),
+        );
+
+        let _guards: Vec<_> = (0..4).map(|_| balance.acquire(0)).collect();
+
+        // Backend 0 can only be picked if drawn twice
+        let picks = (0..100).filter(|_| balance.pick("") == 1).count();
+
+        assert!(picks > 50);
+    }
+
+    #[test]
+    fn it_picks_consistent_hash() {
+        let balance = ProxyBalance::new(
+            ConfigProxyShardBalance::ConsistentHash,
+            &make_backends(&[1, 1, 1]),
+        );
+
+        let keys: Vec<String> = (0..200)
+            .map(|index| format!("bloom:0:a:{}", index))
+            .collect();
+        let picks: Vec<usize> = keys.iter().map(|key| balance.pick(key)).collect();
+
+        assert_eq!(
+            keys.iter().map(|key| balance.pick(key)).collect::<Vec<_>>(),
+            picks
+        );
+        assert!((0..3).all(|backend| picks.contains(&backend)));
+
+        // Removing a backend only moves the keys that were mapped to it
+        let reduced = ProxyBalance::new(
+            ConfigProxyShardBalance::ConsistentHash,
+            &make_backends(&[1, 1]),
+        );
+
+        for (key, pick) in keys.iter().zip(picks) {
+            if pick < 2 {
+                assert_eq!(reduced.pick(key), pick);
+            }
+        }
+    }
+}
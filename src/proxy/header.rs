@@ -4,26 +4,30 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::str::from_utf8;
+use std::time::{Duration, UNIX_EPOCH};
 
 use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode, Uri};
 
-use super::defaults;
+use super::identity::ProxyIdentity;
 use crate::header::request_shard::HeaderRequestBloomRequestShard;
 use crate::APP_CONF;
 
 pub struct ProxyHeader;
 
+pub struct ProxyHeaderConditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
 impl ProxyHeader {
-    pub fn parse_from_request(headers: HeaderMap) -> (HeaderMap, String, u8) {
-        // Request header: 'Authorization'
-        let auth = match headers.get(header::AUTHORIZATION) {
-            None => defaults::REQUEST_AUTHORIZATION_DEFAULT,
-            Some(value) => {
-                from_utf8(value.as_bytes()).unwrap_or(defaults::REQUEST_AUTHORIZATION_DEFAULT)
-            }
-        }
-        .to_string();
+    pub fn parse_from_request(
+        mut headers: HeaderMap,
+        uri: &Uri,
+    ) -> (HeaderMap, Option<String>, u8) {
+        // HTTP/2 requests carry their host in the ':authority' pseudo-header \
+        //   instead of 'Host', so restore it as it gets forwarded to the API
+        Self::set_host_from_authority(&mut headers, uri);
 
         // Request header: 'Bloom-Request-Shard'
         let shard = match headers.get(HeaderRequestBloomRequestShard::header_name()) {
@@ -33,11 +37,25 @@ impl ProxyHeader {
                 .unwrap_or(APP_CONF.proxy.shard_default),
         };
 
+        // Request identity (as configured for shard, defaults to 'Authorization')
+        // Notice: no identity means that the request must bypass the cache
+        let auth = ProxyIdentity::acquire(shard, &headers, uri);
+
         (headers, auth, shard)
     }
 
+    pub fn set_host_from_authority(headers: &mut HeaderMap, uri: &Uri) {
+        if headers.contains_key(header::HOST) == false {
+            if let Some(authority) = uri.authority() {
+                if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                    headers.insert(header::HOST, host);
+                }
+            }
+        }
+    }
+
     pub fn set_etag(headers: &mut HeaderMap, fingerprint: &str) {
-        headers.insert(header::VARY, HeaderValue::from_static("ETag"));
+        Self::append_vary(headers, "ETag");
 
         headers.insert(
             header::ETAG,
@@ -45,6 +63,144 @@ impl ProxyHeader {
         );
     }
 
+    pub fn weaken_etag(headers: &mut HeaderMap) {
+        // Encoded responses are not byte-identical to the response the \
+        //   'ETag' was generated for, thus their 'ETag' must be weak
+        let weak_etag = headers
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with("W/") == false)
+            .and_then(|value| HeaderValue::from_str(&format!("W/{}", value)).ok());
+
+        if let Some(weak_etag) = weak_etag {
+            headers.insert(header::ETAG, weak_etag);
+        }
+    }
+
+    pub fn append_vary(headers: &mut HeaderMap, name: &'static str) {
+        // Append to 'Vary' (do not override existing 'Vary' values, as they \
+        //   may have been sent by the downstream API server)
+        let has_vary_name = headers.get_all(header::VARY).iter().any(|value| {
+            value
+                .to_str()
+                .unwrap_or("")
+                .split(',')
+                .any(|value_name| value_name.trim().eq_ignore_ascii_case(name))
+        });
+
+        if has_vary_name == false {
+            headers.append(header::VARY, HeaderValue::from_static(name));
+        }
+    }
+
+    pub fn set_content_length(
+        headers: &mut HeaderMap,
+        method: &Method,
+        status: &StatusCode,
+        length: usize,
+    ) {
+        // Responses with no content must not carry a 'Content-Length' header \
+        //   (RFC 7230, section 3.3.2)
+        if status.is_informational() == true
+            || *status == StatusCode::NO_CONTENT
+            || *status == StatusCode::NOT_MODIFIED
+        {
+            headers.remove(header::CONTENT_LENGTH);
+
+            return;
+        }
+
+        // Notice: methods listed there must match those for which a body is \
+        //   sent in responses (see 'ProxyServe::respond()')
+        match *method {
+            // 'HEAD' responses carry the length of the body that would have \
+            //   been sent, as stored from the API response
+            Method::HEAD => {}
+            Method::GET | Method::POST | Method::PATCH | Method::PUT | Method::DELETE => {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+            }
+            _ => {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(0));
+            }
+        }
+    }
+
+    pub fn set_last_modified(headers: &mut HeaderMap, modified: u64) {
+        // Notice: the 'Last-Modified' header sent by the API (if any) is \
+        //   served as-is.
+        if headers.contains_key(header::LAST_MODIFIED) == false {
+            let last_modified = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(modified));
+
+            if let Ok(last_modified) = HeaderValue::from_str(&last_modified) {
+                headers.insert(header::LAST_MODIFIED, last_modified);
+            }
+        }
+    }
+
+    pub fn acquire_conditions(
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> Option<ProxyHeaderConditions> {
+        // Conditional requests can only be answered with 'Not Modified' for \
+        //   'GET' and 'HEAD' requests (RFC 9110, section 13.1)
+        if *method != Method::GET && *method != Method::HEAD {
+            return None;
+        }
+
+        let get_header = |name: header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned())
+        };
+
+        let conditions = ProxyHeaderConditions {
+            if_none_match: get_header(header::IF_NONE_MATCH),
+            if_modified_since: get_header(header::IF_MODIFIED_SINCE),
+        };
+
+        if conditions.if_none_match.is_some() || conditions.if_modified_since.is_some() {
+            Some(conditions)
+        } else {
+            None
+        }
+    }
+
+    pub fn check_conditions(conditions: &ProxyHeaderConditions, headers: &HeaderMap) -> bool {
+        let get_header =
+            |name: header::HeaderName| headers.get(name).and_then(|value| value.to_str().ok());
+
+        // Notice: 'If-Modified-Since' is ignored if 'If-None-Match' is set \
+        //   (RFC 9110, section 13.1.3)
+        match (&conditions.if_none_match, &conditions.if_modified_since) {
+            (Some(if_none_match), _) => get_header(header::ETAG)
+                .map(|etag| {
+                    Self::check_if_none_match(
+                        if_none_match,
+                        etag.trim().trim_start_matches("W/").trim_matches('"'),
+                    )
+                })
+                .unwrap_or(false),
+            (None, Some(if_modified_since)) => get_header(header::LAST_MODIFIED)
+                .and_then(|value| httpdate::parse_http_date(value).ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| {
+                    Self::check_if_modified_since(if_modified_since, modified.as_secs())
+                })
+                .unwrap_or(false),
+            (None, None) => false,
+        }
+    }
+
+    pub fn check_if_modified_since(if_modified_since: &str, modified: u64) -> bool {
+        // Invalid dates are ignored (RFC 9110, section 13.1.3)
+        httpdate::parse_http_date(if_modified_since.trim())
+            .ok()
+            .and_then(|since| since.duration_since(UNIX_EPOCH).ok())
+            .map(|since| modified <= since.as_secs())
+            .unwrap_or(false)
+    }
+
     pub fn check_if_none_match(if_none_match: &str, fingerprint: &str) -> bool {
         let value = if_none_match.trim();
 
@@ -71,3 +227,187 @@ impl ProxyHeader {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(128));
+
+        headers
+    }
+
+    #[test]
+    fn it_sets_content_length() {
+        let mut headers = make_headers();
+
+        ProxyHeader::set_content_length(&mut headers, &Method::GET, &StatusCode::OK, 64);
+
+        assert_eq!(headers.get(header::CONTENT_LENGTH).unwrap(), "64");
+    }
+
+    #[test]
+    fn it_removes_content_length_for_no_content() {
+        let mut headers_no_content = make_headers();
+        let mut headers_not_modified = make_headers();
+
+        ProxyHeader::set_content_length(
+            &mut headers_no_content,
+            &Method::GET,
+            &StatusCode::NO_CONTENT,
+            0,
+        );
+        ProxyHeader::set_content_length(
+            &mut headers_not_modified,
+            &Method::GET,
+            &StatusCode::NOT_MODIFIED,
+            0,
+        );
+
+        assert!(headers_no_content.get(header::CONTENT_LENGTH).is_none());
+        assert!(headers_not_modified.get(header::CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn it_keeps_content_length_for_head() {
+        let mut headers = make_headers();
+
+        ProxyHeader::set_content_length(&mut headers, &Method::HEAD, &StatusCode::OK, 0);
+
+        assert_eq!(headers.get(header::CONTENT_LENGTH).unwrap(), "128");
+    }
+
+    #[test]
+    fn it_empties_content_length_for_options() {
+        let mut headers = make_headers();
+
+        ProxyHeader::set_content_length(&mut headers, &Method::OPTIONS, &StatusCode::OK, 64);
+
+        assert_eq!(headers.get(header::CONTENT_LENGTH).unwrap(), "0");
+    }
+
+    #[test]
+    fn it_checks_if_modified_since() {
+        assert!(ProxyHeader::check_if_modified_since(
+            "Thu, 01 Jan 2026 00:00:00 GMT",
+            1767225600
+        ));
+        assert!(!ProxyHeader::check_if_modified_since(
+            "Thu, 01 Jan 2026 00:00:00 GMT",
+            1767225601
+        ));
+        assert!(!ProxyHeader::check_if_modified_since(
+            "yesterday",
+            1767225600
+        ));
+    }
+
+    #[test]
+    fn it_checks_conditions() {
+        let mut headers = HeaderMap::new();
+
+        ProxyHeader::set_etag(&mut headers, "7e1d3f");
+        ProxyHeader::set_last_modified(&mut headers, 1767225600);
+
+        assert_eq!(
+            headers.get(header::LAST_MODIFIED).unwrap(),
+            "Thu, 01 Jan 2026 00:00:00 GMT"
+        );
+
+        let make_conditions = |if_none_match: Option<&str>, if_modified_since: Option<&str>| {
+            let mut request_headers = HeaderMap::new();
+
+            if let Some(if_none_match) = if_none_match {
+                request_headers.insert(
+                    header::IF_NONE_MATCH,
+                    HeaderValue::from_str(if_none_match).unwrap(),
+                );
+            }
+            if let Some(if_modified_since) = if_modified_since {
+                request_headers.insert(
+                    header::IF_MODIFIED_SINCE,
+                    HeaderValue::from_str(if_modified_since).unwrap(),
+                );
+            }
+
+            ProxyHeader::acquire_conditions(&Method::GET, &request_headers)
+        };
+
+        assert!(make_conditions(None, None).is_none());
+        assert!(ProxyHeader::acquire_conditions(&Method::POST, &headers).is_none());
+
+        assert!(ProxyHeader::check_conditions(
+            &make_conditions(Some("W/\"7e1d3f\""), None).unwrap(),
+            &headers
+        ));
+        assert!(!ProxyHeader::check_conditions(
+            &make_conditions(Some("\"other\""), Some("Fri, 02 Jan 2026 00:00:00 GMT")).unwrap(),
+            &headers
+        ));
+        assert!(ProxyHeader::check_conditions(
+            &make_conditions(None, Some("Fri, 02 Jan 2026 00:00:00 GMT")).unwrap(),
+            &headers
+        ));
+        assert!(!ProxyHeader::check_conditions(
+            &make_conditions(None, Some("Wed, 31 Dec 2025 00:00:00 GMT")).unwrap(),
+            &headers
+        ));
+    }
+
+    #[test]
+    fn it_weakens_etag() {
+        let mut headers = HeaderMap::new();
+
+        ProxyHeader::set_etag(&mut headers, "7e1d3f");
+        ProxyHeader::weaken_etag(&mut headers);
+
+        assert_eq!(headers.get(header::ETAG).unwrap(), "W/\"7e1d3f\"");
+
+        ProxyHeader::weaken_etag(&mut headers);
+
+        assert_eq!(headers.get(header::ETAG).unwrap(), "W/\"7e1d3f\"");
+        assert!(ProxyHeader::check_if_none_match("W/\"7e1d3f\"", "7e1d3f"));
+    }
+
+    #[test]
+    fn it_appends_vary_once() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+        ProxyHeader::append_vary(&mut headers, "Accept-Encoding");
+        ProxyHeader::set_etag(&mut headers, "7e1d3f");
+        ProxyHeader::set_etag(&mut headers, "7e1d3f");
+
+        assert_eq!(
+            headers.get_all(header::VARY).iter().collect::<Vec<_>>(),
+            vec!["accept-encoding", "ETag"]
+        );
+    }
+
+    #[test]
+    fn it_sets_host_from_authority() {
+        let mut headers = HeaderMap::new();
+
+        ProxyHeader::set_host_from_authority(&mut headers, &"/feed".parse().unwrap());
+
+        assert!(!headers.contains_key(header::HOST));
+
+        ProxyHeader::set_host_from_authority(
+            &mut headers,
+            &"https://api.example.com/feed".parse().unwrap(),
+        );
+
+        assert_eq!(headers.get(header::HOST).unwrap(), "api.example.com");
+
+        ProxyHeader::set_host_from_authority(
+            &mut headers,
+            &"https://other.example.com/feed".parse().unwrap(),
+        );
+
+        assert_eq!(headers.get(header::HOST).unwrap(), "api.example.com");
+    }
+}
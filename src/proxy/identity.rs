@@ -0,0 +1,231 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::borrow::Cow;
+use std::str::from_utf8;
+
+use hyper::header::{self, HeaderMap};
+use hyper::Uri;
+use percent_encoding::percent_decode_str;
+
+use super::defaults;
+use super::token::ProxyToken;
+use crate::config::config::{ConfigProxyShardIdentity, ConfigProxyShardIdentitySource};
+use crate::{APP_CONF, APP_PROXY_TOKEN_KEYRINGS};
+
+pub struct ProxyIdentity;
+
+impl ProxyIdentity {
+    pub fn acquire(shard: u8, headers: &HeaderMap, uri: &Uri) -> Option<String> {
+        let shard_config = APP_CONF.proxy.find_shard(shard);
+
+        let identity = match shard_config {
+            Some(shard_config) if shard_config.identity.is_empty() == false => {
+                Self::acquire_from_sources(&shard_config.identity, headers, uri)
+            }
+            _ => Self::acquire_from_authorization(headers),
+        };
+
+        // Identity is a JWT? Use the configured claim as identity instead
+        // Notice: a token that cannot be validated (eg. expired) yields no \
+        //   identity, meaning that the request must bypass the cache.
+        match (
+            shard_config.and_then(|shard_config| shard_config.jwt.as_ref()),
+            APP_PROXY_TOKEN_KEYRINGS.get(&shard),
+        ) {
+            (Some(jwt), Some(keyring)) if identity.is_empty() == false => {
+                ProxyToken::acquire_claim(&identity, &jwt.claim, keyring)
+            }
+            _ => Some(identity),
+        }
+    }
+
+    fn acquire_from_sources(
+        sources: &[ConfigProxyShardIdentity],
+        headers: &HeaderMap,
+        uri: &Uri,
+    ) -> String {
+        // Use the first identity source that is set in the request (in order)
+        sources
+            .iter()
+            .find_map(|source| Self::acquire_from_source(source, headers, uri))
+            .unwrap_or_else(|| defaults::REQUEST_AUTHORIZATION_DEFAULT.to_string())
+    }
+
+    fn acquire_from_authorization(headers: &HeaderMap) -> String {
+        // Request header: 'Authorization'
+        match headers.get(header::AUTHORIZATION) {
+            None => defaults::REQUEST_AUTHORIZATION_DEFAULT,
+            Some(value) => {
+                from_utf8(value.as_bytes()).unwrap_or(defaults::REQUEST_AUTHORIZATION_DEFAULT)
+            }
+        }
+        .to_string()
+    }
+
+    fn acquire_from_source(
+        source: &ConfigProxyShardIdentity,
+        headers: &HeaderMap,
+        uri: &Uri,
+    ) -> Option<String> {
+        let value = match source.source {
+            ConfigProxyShardIdentitySource::Header => headers
+                .get(source.name.as_str())
+                .and_then(|value| from_utf8(value.as_bytes()).ok())
+                .map(Cow::Borrowed),
+            ConfigProxyShardIdentitySource::Cookie => Self::find_cookie(headers, &source.name),
+            ConfigProxyShardIdentitySource::Query => {
+                Self::find_query_param(uri.query(), &source.name)
+            }
+        }?;
+
+        let mut value = value.trim();
+
+        // Trim authorization scheme? (eg. 'Bearer' or 'Basic')
+        if source.trim_scheme == true {
+            if let Some((_, credentials)) = value.split_once(' ') {
+                value = credentials.trim();
+            }
+        }
+
+        if value.is_empty() == true {
+            return None;
+        }
+
+        Some(if source.lowercase == true {
+            value.to_lowercase()
+        } else {
+            value.to_string()
+        })
+    }
+
+    fn find_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<Cow<'a, str>> {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(pair_name, _)| *pair_name == name)
+            .map(|(_, pair_value)| Cow::Borrowed(pair_value.trim_matches('"')))
+    }
+
+    fn find_query_param<'a>(query: Option<&'a str>, name: &str) -> Option<Cow<'a, str>> {
+        query?
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(param_name, _)| percent_decode_str(param_name).decode_utf8_lossy() == name)
+            .map(|(_, param_value)| percent_decode_str(param_value).decode_utf8_lossy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn make_source(
+        source: ConfigProxyShardIdentitySource,
+        name: &str,
+        trim_scheme: bool,
+        lowercase: bool,
+    ) -> ConfigProxyShardIdentity {
+        ConfigProxyShardIdentity {
+            source,
+            name: name.to_string(),
+            trim_scheme,
+            lowercase,
+        }
+    }
+
+    #[test]
+    fn it_acquires_identity_from_authorization() {
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(ProxyIdentity::acquire_from_authorization(&headers), "");
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer AbC"),
+        );
+
+        assert_eq!(
+            ProxyIdentity::acquire_from_authorization(&headers),
+            "Bearer AbC"
+        );
+    }
+
+    #[test]
+    fn it_acquires_identity_from_sources() {
+        let sources = vec![
+            make_source(
+                ConfigProxyShardIdentitySource::Header,
+                "X-API-Key",
+                false,
+                true,
+            ),
+            make_source(
+                ConfigProxyShardIdentitySource::Cookie,
+                "session",
+                false,
+                false,
+            ),
+            make_source(
+                ConfigProxyShardIdentitySource::Query,
+                "api_key",
+                false,
+                false,
+            ),
+            make_source(
+                ConfigProxyShardIdentitySource::Header,
+                "Authorization",
+                true,
+                false,
+            ),
+        ];
+
+        let uri: Uri = "/feed?page=1&api%5Fkey=k%20y".parse().unwrap();
+
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(
+            ProxyIdentity::acquire_from_sources(&sources, &headers, &"/feed".parse().unwrap()),
+            ""
+        );
+
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer AbC"),
+        );
+
+        assert_eq!(
+            ProxyIdentity::acquire_from_sources(&sources, &headers, &"/feed".parse().unwrap()),
+            "AbC"
+        );
+        assert_eq!(
+            ProxyIdentity::acquire_from_sources(&sources, &headers, &uri),
+            "k y"
+        );
+
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; session=\"s1\""),
+        );
+
+        assert_eq!(
+            ProxyIdentity::acquire_from_sources(&sources, &headers, &uri),
+            "s1"
+        );
+
+        headers.insert("x-api-key", HeaderValue::from_static(" KeY "));
+
+        assert_eq!(
+            ProxyIdentity::acquire_from_sources(&sources, &headers, &uri),
+            "key"
+        );
+    }
+}
@@ -10,7 +10,7 @@ use std::sync::{Arc, Mutex};
 use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
 
 lazy_static! {
-    static ref PROXY_LOCK_MAP: Mutex<HashMap<String, Arc<AsyncMutex<()>>>> =
+    static ref PROXY_LOCK_MAP: Mutex<HashMap<String, Arc<AsyncMutex<bool>>>> =
         Mutex::new(HashMap::new());
 }
 
@@ -18,21 +18,14 @@ pub struct ProxyLock;
 
 pub struct ProxyLockGuard {
     ns: String,
-    mutex: Arc<AsyncMutex<()>>,
-    guard: Option<OwnedMutexGuard<()>>,
+    mutex: Arc<AsyncMutex<bool>>,
+    guard: Option<OwnedMutexGuard<bool>>,
 }
 
 impl ProxyLock {
     pub async fn acquire(ns: &str) -> (ProxyLockGuard, bool) {
         // Acquire a mutex
-        let mutex = {
-            PROXY_LOCK_MAP
-                .lock()
-                .expect("lock map poisoned")
-                .entry(ns.to_string())
-                .or_insert_with(|| Arc::new(AsyncMutex::new(())))
-                .clone()
-        };
+        let mutex = Self::acquire_mutex(ns);
 
         // Attempt to acquire the lock immediately; if it fails, wait for it.
         //   The boolean value indicates whether we had to wait or not \
@@ -54,6 +47,44 @@ impl ProxyLock {
             had_to_wait,
         )
     }
+
+    pub fn try_acquire(ns: &str) -> Option<ProxyLockGuard> {
+        // Acquire a mutex
+        let mutex = Self::acquire_mutex(ns);
+
+        // Attempt to acquire the lock immediately, and give up if it is \
+        //   already held (ie. a request is already being tunneled)
+        let guard = Arc::clone(&mutex).try_lock_owned().ok()?;
+
+        Some(ProxyLockGuard {
+            ns: ns.to_string(),
+            mutex,
+            guard: Some(guard),
+        })
+    }
+
+    fn acquire_mutex(ns: &str) -> Arc<AsyncMutex<bool>> {
+        PROXY_LOCK_MAP
+            .lock()
+            .expect("lock map poisoned")
+            .entry(ns.to_string())
+            .or_insert_with(|| Arc::new(AsyncMutex::new(false)))
+            .clone()
+    }
+}
+
+impl ProxyLockGuard {
+    pub fn has_failed(&self) -> bool {
+        // Whether the previous lock holder could not get a response from the \
+        //   API (this lets queued requests avoid hammering a down API)
+        self.guard.as_deref().copied().unwrap_or(false)
+    }
+
+    pub fn set_failed(&mut self, failed: bool) {
+        if let Some(ref mut guard) = self.guard {
+            **guard = failed;
+        }
+    }
 }
 
 impl Drop for ProxyLockGuard {
@@ -4,10 +4,16 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+mod balance;
 mod defaults;
 mod header;
+mod identity;
 mod lock;
+mod range;
+mod socket;
+mod tls;
 
 pub mod logger;
 pub mod serve;
+pub mod token;
 pub mod tunnel;
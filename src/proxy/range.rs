@@ -0,0 +1,437 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::ops::Range;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use rand::distr::Alphanumeric;
+use rand::{self, RngExt};
+
+pub struct ProxyRange;
+
+pub struct ProxyRangeRequest {
+    range: HeaderValue,
+    if_range: Option<HeaderValue>,
+}
+
+#[derive(Debug, PartialEq)]
+enum ProxyRangeSpec {
+    Full,
+    Partial(Vec<Range<usize>>),
+    Unsatisfiable,
+}
+
+static RANGE_UNIT: &'static str = "bytes";
+static RANGE_BOUNDARY_SIZE: usize = 24;
+
+// Serve full response to requests with too many ranges, as this is a \
+//   common way to amplify the size of responses (RFC 9110, section 17.15)
+const RANGE_MAX_COUNT: usize = 16;
+
+impl ProxyRange {
+    pub fn from_request(method: &Method, headers: &HeaderMap) -> Option<ProxyRangeRequest> {
+        // Notice: 'Range' is only defined for 'GET' requests (RFC 9110, \
+        //   section 14.2)
+        if *method != Method::GET {
+            return None;
+        }
+
+        headers.get(header::RANGE).map(|range| ProxyRangeRequest {
+            range: range.to_owned(),
+            if_range: headers.get(header::IF_RANGE).cloned(),
+        })
+    }
+
+    pub fn slice(
+        request: &ProxyRangeRequest,
+        status: StatusCode,
+        headers: &mut HeaderMap,
+        body: Bytes,
+    ) -> (StatusCode, Bytes) {
+        // Only full responses can be sliced into partial responses
+        if status != StatusCode::OK {
+            return (status, body);
+        }
+
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static(RANGE_UNIT));
+
+        // Range is conditional, and response does not match? Serve it in full
+        if let Some(ref if_range) = request.if_range {
+            if Self::check_if_range(if_range, headers) == false {
+                return (status, body);
+            }
+        }
+
+        match Self::parse(&request.range, body.len()) {
+            ProxyRangeSpec::Full => (status, body),
+            ProxyRangeSpec::Unsatisfiable => {
+                headers.insert(
+                    header::CONTENT_RANGE,
+                    HeaderValue::from_str(&format!("{} */{}", RANGE_UNIT, body.len())).unwrap(),
+                );
+
+                (StatusCode::RANGE_NOT_SATISFIABLE, Bytes::new())
+            }
+            ProxyRangeSpec::Partial(ranges) => {
+                if ranges.len() == 1 {
+                    headers.insert(
+                        header::CONTENT_RANGE,
+                        Self::make_content_range(&ranges[0], body.len()),
+                    );
+
+                    (StatusCode::PARTIAL_CONTENT, body.slice(ranges[0].clone()))
+                } else {
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        Self::make_multipart(&ranges, headers, body),
+                    )
+                }
+            }
+        }
+    }
+
+    fn check_if_range(if_range: &HeaderValue, headers: &HeaderMap) -> bool {
+        let if_range = if_range.to_str().unwrap_or("").trim();
+
+        // Notice: 'If-Range' holds either an entity tag, which must strongly \
+        //   match the response entity tag, or a date, which must exactly \
+        //   match the response last modification date.
+        if if_range.starts_with('"') == true || if_range.starts_with("W/") == true {
+            if_range.starts_with('"') == true
+                && headers
+                    .get(header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .map(|etag| etag.trim() == if_range)
+                    .unwrap_or(false)
+        } else {
+            match (
+                httpdate::parse_http_date(if_range).ok(),
+                headers
+                    .get(header::LAST_MODIFIED)
+                    .and_then(|last_modified| last_modified.to_str().ok())
+                    .and_then(|last_modified| httpdate::parse_http_date(last_modified).ok()),
+            ) {
+                (Some(if_range_date), Some(last_modified_date)) => {
+                    if_range_date == last_modified_date
+                }
+                _ => false,
+            }
+        }
+    }
+
+    fn parse(range: &HeaderValue, length: usize) -> ProxyRangeSpec {
+        // Notice: invalid 'Range' values are ignored, meaning that the full \
+        //   response gets served (RFC 9110, section 14.2)
+        let specs = match range
+            .to_str()
+            .ok()
+            .and_then(|range| range.trim().split_once('='))
+        {
+            Some((unit, specs)) if unit.trim().eq_ignore_ascii_case(RANGE_UNIT) => specs,
+            _ => return ProxyRangeSpec::Full,
+        };
+
+        let mut ranges = Vec::new();
+
+        for spec in specs.split(',').map(|spec| spec.trim()) {
+            if spec.is_empty() == true {
+                continue;
+            }
+
+            let (first, last) = match spec.split_once('-') {
+                Some((first, last)) => (first.trim(), last.trim()),
+                None => return ProxyRangeSpec::Full,
+            };
+
+            let range = if first.is_empty() == true {
+                // Suffix range (ie. last N bytes)
+                match last.parse::<usize>() {
+                    Ok(suffix) if suffix > 0 && length > 0 => length.saturating_sub(suffix)..length,
+                    Ok(_) => continue,
+                    Err(_) => return ProxyRangeSpec::Full,
+                }
+            } else {
+                let first = match first.parse::<usize>() {
+                    Ok(first) => first,
+                    Err(_) => return ProxyRangeSpec::Full,
+                };
+
+                let last = if last.is_empty() == true {
+                    None
+                } else {
+                    match last.parse::<usize>() {
+                        Ok(last) if last >= first => Some(last),
+                        _ => return ProxyRangeSpec::Full,
+                    }
+                };
+
+                // Range starts past the end of the body? Not satisfiable
+                if first >= length {
+                    continue;
+                }
+
+                // Notice: last positions may be as large as the client \
+                //   wants, thus they must not overflow once made exclusive.
+                first
+                    ..last
+                        .map(|last| last.saturating_add(1).min(length))
+                        .unwrap_or(length)
+            };
+
+            ranges.push(range);
+        }
+
+        if ranges.len() > RANGE_MAX_COUNT {
+            ProxyRangeSpec::Full
+        } else if ranges.is_empty() == true {
+            ProxyRangeSpec::Unsatisfiable
+        } else {
+            ProxyRangeSpec::Partial(Self::coalesce(ranges))
+        }
+    }
+
+    fn coalesce(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+        // Merge overlapping or adjacent ranges, so that the same bytes \
+        //   cannot be requested multiple times to amplify the size of \
+        //   responses (RFC 9110, section 14.2)
+        ranges.sort_by_key(|range| range.start);
+
+        let mut coalesced: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+
+        for range in ranges {
+            match coalesced.last_mut() {
+                Some(previous) if range.start <= previous.end => {
+                    previous.end = previous.end.max(range.end);
+                }
+                _ => coalesced.push(range),
+            }
+        }
+
+        coalesced
+    }
+
+    fn make_content_range(range: &Range<usize>, length: usize) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{} {}-{}/{}",
+            RANGE_UNIT,
+            range.start,
+            range.end - 1,
+            length
+        ))
+        .unwrap()
+    }
+
+    fn make_multipart(ranges: &[Range<usize>], headers: &mut HeaderMap, body: Bytes) -> Bytes {
+        let boundary: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(RANGE_BOUNDARY_SIZE)
+            .map(char::from)
+            .collect();
+
+        // Each part holds the content type of the full response (if any)
+        let content_type = headers.remove(header::CONTENT_TYPE);
+
+        let mut multipart = BytesMut::new();
+
+        for range in ranges {
+            multipart.put_slice(format!("\r\n--{}\r\n", boundary).as_bytes());
+
+            if let Some(ref content_type) = content_type {
+                multipart.put_slice(b"Content-Type: ");
+                multipart.put_slice(content_type.as_bytes());
+                multipart.put_slice(b"\r\n");
+            }
+
+            multipart.put_slice(b"Content-Range: ");
+            multipart.put_slice(Self::make_content_range(range, body.len()).as_bytes());
+            multipart.put_slice(b"\r\n\r\n");
+            multipart.put_slice(&body[range.clone()]);
+        }
+
+        multipart.put_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
+        );
+
+        multipart.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_request(range: &'static str, if_range: Option<&'static str>) -> ProxyRangeRequest {
+        ProxyRangeRequest {
+            range: HeaderValue::from_static(range),
+            if_range: if_range.map(HeaderValue::from_static),
+        }
+    }
+
+    fn make_partial(ranges: &[(usize, usize)]) -> ProxyRangeSpec {
+        ProxyRangeSpec::Partial(ranges.iter().map(|&(start, end)| start..end).collect())
+    }
+
+    fn make_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        headers.insert(header::ETAG, HeaderValue::from_static("\"5e1ac6f3\""));
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+
+        headers
+    }
+
+    #[test]
+    fn it_parses_ranges() {
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=0-4"), 10),
+            make_partial(&[(0, 5)])
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=8-, 0-1, -3"), 10),
+            make_partial(&[(0, 2), (7, 10)])
+        );
+        assert_eq!(
+            ProxyRange::parse(
+                &HeaderValue::from_static("bytes=5-18446744073709551615"),
+                10
+            ),
+            make_partial(&[(5, 10)])
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=10-, -0"), 10),
+            ProxyRangeSpec::Unsatisfiable
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=5-2"), 10),
+            ProxyRangeSpec::Full
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("items=0-4"), 10),
+            ProxyRangeSpec::Full
+        );
+    }
+
+    #[test]
+    fn it_coalesces_ranges() {
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=8-, -3, 2-100"), 10),
+            make_partial(&[(2, 10)])
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=0-, 0-, 0-, 0-"), 10),
+            make_partial(&[(0, 10)])
+        );
+        assert_eq!(
+            ProxyRange::parse(&HeaderValue::from_static("bytes=4-5, 0-1, 2-3, 7-8"), 10),
+            make_partial(&[(0, 6), (7, 9)])
+        );
+    }
+
+    #[test]
+    fn it_slices_single_range() {
+        let mut headers = make_headers();
+
+        let (status, body) = ProxyRange::slice(
+            &make_request("bytes=2-5", None),
+            StatusCode::OK,
+            &mut headers,
+            Bytes::from_static(b"0123456789"),
+        );
+
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, Bytes::from_static(b"2345"));
+        assert_eq!(headers.get(header::CONTENT_RANGE).unwrap(), "bytes 2-5/10");
+        assert_eq!(headers.get(header::ACCEPT_RANGES).unwrap(), "bytes");
+    }
+
+    #[test]
+    fn it_slices_multiple_ranges() {
+        let mut headers = make_headers();
+
+        let (status, body) = ProxyRange::slice(
+            &make_request("bytes=0-1,-2", None),
+            StatusCode::OK,
+            &mut headers,
+            Bytes::from_static(b"0123456789"),
+        );
+
+        let content_type = headers.get(header::CONTENT_TYPE).unwrap().to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            body,
+            Bytes::from(format!(
+                "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{b}--\r\n",
+                b = boundary
+            ))
+        );
+    }
+
+    #[test]
+    fn it_rejects_unsatisfiable_range() {
+        let mut headers = make_headers();
+
+        let (status, body) = ProxyRange::slice(
+            &make_request("bytes=20-", None),
+            StatusCode::OK,
+            &mut headers,
+            Bytes::from_static(b"0123456789"),
+        );
+
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert!(body.is_empty());
+        assert_eq!(headers.get(header::CONTENT_RANGE).unwrap(), "bytes */10");
+    }
+
+    #[test]
+    fn it_checks_if_range() {
+        let body = Bytes::from_static(b"0123456789");
+
+        for (if_range, expected_status) in [
+            ("\"5e1ac6f3\"", StatusCode::PARTIAL_CONTENT),
+            ("\"00000000\"", StatusCode::OK),
+            ("W/\"5e1ac6f3\"", StatusCode::OK),
+            ("Wed, 21 Oct 2015 07:28:00 GMT", StatusCode::PARTIAL_CONTENT),
+            ("Thu, 22 Oct 2015 07:28:00 GMT", StatusCode::OK),
+        ] {
+            let (status, _) = ProxyRange::slice(
+                &make_request("bytes=0-1", Some(if_range)),
+                StatusCode::OK,
+                &mut make_headers(),
+                body.clone(),
+            );
+
+            assert_eq!(status, expected_status, "{}", if_range);
+        }
+    }
+
+    #[test]
+    fn it_ignores_range_for_non_full_response() {
+        let (status, body) = ProxyRange::slice(
+            &make_request("bytes=0-1", None),
+            StatusCode::NOT_FOUND,
+            &mut make_headers(),
+            Bytes::from_static(b"not found"),
+        );
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, Bytes::from_static(b"not found"));
+    }
+}
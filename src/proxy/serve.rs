@@ -9,30 +9,33 @@ use std::pin::Pin;
 use std::time::Instant;
 
 use bytes::Bytes;
-use http_body_util::Full;
-use httparse;
+use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
 use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
-use hyper::{Method, Request, Response, StatusCode, Uri, Version};
-use itertools::{Itertools, Position};
+use hyper::{Method, Request, Response, StatusCode, Uri};
 
-use super::header::ProxyHeader;
+use super::header::{ProxyHeader, ProxyHeaderConditions};
 use super::lock::ProxyLock;
+use super::range::{ProxyRange, ProxyRangeRequest};
 use super::tunnel::ProxyTunnel;
 use crate::cache::check::CacheCheck;
-use crate::cache::read::CacheRead;
+use crate::cache::encoding::{CacheEncoding, CacheEncodingValue};
+use crate::cache::envelope::CacheEnvelope;
+use crate::cache::policy::{CachePolicy, CachePolicyRequest};
+use crate::cache::read::{CacheRead, CacheReadError};
 use crate::cache::route::CacheRoute;
+use crate::cache::store::CacheStoreMeta;
 use crate::cache::write::CacheWrite;
 use crate::header::janitor::HeaderJanitor;
 use crate::header::status::{HeaderBloomStatus, HeaderBloomStatusValue};
-use crate::{APP_CONF, LINE_FEED};
+use crate::APP_CONF;
 
 pub struct ProxyServe;
 
-const CACHED_PARSE_MAX_HEADERS: usize = 100;
-
 pub type ProxyServeError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
+type ProxyServeFetchResult = Result<(String, Option<CacheEnvelope>), Option<CacheStoreMeta>>;
+
 pub type ProxyServeResponseFuture =
     Pin<Box<dyn Future<Output = Result<Response<Full<Bytes>>, ProxyServeError>> + Send>>;
 
@@ -68,7 +71,7 @@ impl ProxyServe {
             req.method().clone(),
             status,
             headers,
-            format!("{}", status),
+            Bytes::from(format!("{}", status)),
         ))
     }
 
@@ -79,46 +82,305 @@ impl ProxyServe {
         let uri = parts.uri;
         let version = parts.version;
 
-        let (headers, auth, shard) = ProxyHeader::parse_from_request(parts.headers);
+        let (headers, auth, shard) = ProxyHeader::parse_from_request(parts.headers, &uri);
+
+        // Acquire range from request (if any), as partial responses get \
+        //   sliced from full responses, be them cached or not
+        let range = ProxyRange::from_request(&method, &headers);
+
+        // Acquire the encoding to serve the response with (if any)
+        let encoding = Self::negotiate_encoding(&method, &headers);
+
+        // Acquire conditions from request (if any), as 'Not Modified' \
+        //   responses get derived from full responses, be them cached or not
+        let conditions = ProxyHeader::acquire_conditions(&method, &headers);
+
+        // Request identity could not be validated (eg. expired token)? Tunnel \
+        //   it without reading nor writing cache.
+        let auth = match auth {
+            Some(auth) => auth,
+            None => {
+                info!("tunneling directly for unidentified request");
+
+                return Self::dispatch_ranged(
+                    range,
+                    Self::dispatch_conditional(
+                        conditions,
+                        Self::dispatch_encoded(
+                            encoding,
+                            Box::pin(Self::dispatch_direct(shard, method, uri, headers, body)),
+                        ),
+                    ),
+                );
+            }
+        };
 
         let auth_hash = CacheRoute::hash(&auth);
 
-        let origin = headers
-            .get(header::ORIGIN)
-            .and_then(|origin| origin.to_str().ok());
-
-        let (ns, ns_mask) = CacheRoute::gen_key_cache(
-            shard,
-            &auth_hash,
-            version,
-            &method,
-            uri.path(),
-            uri.query(),
-            origin,
+        let (ns, ns_mask) = CacheRoute::gen_key_cache_from_request(
+            shard, &auth_hash, version, &method, &uri, &headers,
         );
 
         info!("tunneling for ns = {}", ns);
 
-        Box::pin(async move {
+        let is_head = method == Method::HEAD;
+
+        let response = Box::pin(async move {
             let fetch_result = Self::fetch_cached_data(shard, &ns, &method, &headers)
                 .await
                 .map_err(|_| Self::make_proxy_error("fetch error"))?;
 
             match fetch_result {
                 Ok(value) => {
-                    Self::dispatch_cached(
-                        shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
-                        value.0, value.1,
-                    )
-                    .await
+                    Self::dispatch_cached(method, value.0, value.1, HeaderBloomStatusValue::Hit)
+                        .await
                 }
-                Err(_) => {
+                Err(stale) => {
+                    // Notice: 'HEAD' requests are served from cached 'GET' \
+                    //   responses, though responses to 'HEAD' requests have \
+                    //   no body and thus cannot be cached. Tunnel them as \
+                    //   'GET' requests so that cache gets populated (if \
+                    //   enabled), or directly.
+                    let method = match method {
+                        Method::HEAD if APP_CONF.proxy.tunnel_head_as_get == true => Method::GET,
+                        Method::HEAD => {
+                            return Self::dispatch_direct(shard, method, uri, headers, body).await;
+                        }
+                        method => method,
+                    };
+
+                    // Cache is stale, though still within its revalidate \
+                    //   window? Serve it right away, and refresh it in the \
+                    //   background.
+                    if let Some(ref stale_meta) = stale {
+                        if stale_meta.can_revalidate() == true {
+                            if let Some(stale_result) = Self::dispatch_stale(
+                                &method,
+                                stale_meta,
+                                HeaderBloomStatusValue::StaleRevalidate,
+                            )
+                            .await
+                            {
+                                Self::spawn_refresh(
+                                    ns.to_owned(),
+                                    Self::dispatch_tunnel(
+                                        shard, ns, ns_mask, auth_hash, method, uri, headers, None,
+                                        stale,
+                                    ),
+                                );
+
+                                return stale_result;
+                            }
+                        }
+                    }
+
+                    // Client requested a cached response only? Do not tunnel
+                    if Self::acquire_request_policy(shard, &headers).only_if_cached == true {
+                        return Self::dispatch_uncached(&method).await;
+                    }
+
                     Self::queue_tunnel_over_proxy(
-                        shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
+                        shard, ns, ns_mask, auth_hash, method, uri, headers, body, stale,
                     )
                     .await
                 }
             }
+        });
+
+        let response = if is_head == true {
+            Self::dispatch_headless(response)
+        } else {
+            response
+        };
+
+        Self::dispatch_ranged(
+            range,
+            Self::dispatch_conditional(conditions, Self::dispatch_encoded(encoding, response)),
+        )
+    }
+
+    fn dispatch_headless(response: ProxyServeResponseFuture) -> ProxyServeResponseFuture {
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+
+            let body_bytes = body
+                .collect()
+                .await
+                .map_err(|err| -> ProxyServeError { Box::new(err) })?
+                .to_bytes();
+
+            // Response got tunneled as a 'GET' response? Strip its body, \
+            //   and frame it with the length of the stripped body
+            if body_bytes.is_empty() == false {
+                debug!("stripped body from response to head request");
+
+                ProxyHeader::set_content_length(
+                    &mut parts.headers,
+                    &Method::GET,
+                    &parts.status,
+                    body_bytes.len(),
+                );
+            }
+
+            Ok(Response::from_parts(parts, Full::new(Bytes::new())))
+        })
+    }
+
+    fn negotiate_encoding(method: &Method, headers: &HeaderMap) -> Option<CacheEncodingValue> {
+        // Notice: partial responses are sliced from identity responses, as \
+        //   ranges would otherwise apply to encoded bodies.
+        if APP_CONF.proxy.compress_responses == false || headers.contains_key(header::RANGE) {
+            return None;
+        }
+
+        // Notice: methods listed there must match those for which a body is \
+        //   sent in responses (see 'ProxyServe::respond()')
+        match *method {
+            Method::GET | Method::POST | Method::PATCH | Method::PUT | Method::DELETE => {
+                CacheEncoding::negotiate(headers)
+            }
+            _ => None,
+        }
+    }
+
+    fn dispatch_encoded(
+        encoding: Option<CacheEncodingValue>,
+        response: ProxyServeResponseFuture,
+    ) -> ProxyServeResponseFuture {
+        // Responses are not encoded by Bloom? Serve response as-is
+        if APP_CONF.proxy.compress_responses == false {
+            return response;
+        }
+
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+
+            let mut body_bytes = body
+                .collect()
+                .await
+                .map_err(|err| -> ProxyServeError { Box::new(err) })?
+                .to_bytes();
+
+            // Response is encoded already (eg. served from an encoded body \
+            //   stored in cache)? Only tell that it varies on encoding.
+            if CacheEncoding::is_encoded(&parts.headers) == true {
+                ProxyHeader::append_vary(&mut parts.headers, "Accept-Encoding");
+            } else if body_bytes.len() >= APP_CONF.proxy.compress_responses_above_bytes
+                && CacheEncoding::is_compressible(&parts.status, &parts.headers) == true
+            {
+                // Notice: 'Vary' is set even if the response does not get \
+                //   encoded, as another client may get it encoded.
+                ProxyHeader::append_vary(&mut parts.headers, "Accept-Encoding");
+
+                if let Some(encoding) = encoding {
+                    match CacheEncoding::encode(encoding, &body_bytes) {
+                        Ok(encoded_bytes) => {
+                            debug!(
+                                "encoded response with: {} from {} bytes to {} bytes",
+                                encoding.to_str(),
+                                body_bytes.len(),
+                                encoded_bytes.len()
+                            );
+
+                            body_bytes = encoded_bytes;
+
+                            Self::set_content_encoding(&mut parts.headers, encoding);
+
+                            ProxyHeader::set_content_length(
+                                &mut parts.headers,
+                                &Method::GET,
+                                &parts.status,
+                                body_bytes.len(),
+                            );
+                        }
+                        Err(err) => {
+                            error!("could not encode response because: {}", err);
+                        }
+                    }
+                }
+            }
+
+            Ok(Response::from_parts(parts, Full::new(body_bytes)))
+        })
+    }
+
+    fn dispatch_conditional(
+        conditions: Option<ProxyHeaderConditions>,
+        response: ProxyServeResponseFuture,
+    ) -> ProxyServeResponseFuture {
+        // Not a conditional request? Serve response as-is
+        let conditions = match conditions {
+            Some(conditions) => conditions,
+            None => return response,
+        };
+
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+
+            // Notice: responses served from cache are already checked against \
+            //   conditions, though responses tunneled to the API are not, as \
+            //   conditional request headers do not get forwarded to the API.
+            if parts.status == StatusCode::OK
+                && ProxyHeader::check_conditions(&conditions, &parts.headers) == true
+            {
+                debug!("answered conditional request with not modified");
+
+                parts.status = StatusCode::NOT_MODIFIED;
+
+                ProxyHeader::set_content_length(&mut parts.headers, &Method::GET, &parts.status, 0);
+
+                return Ok(Response::from_parts(parts, Full::new(Bytes::new())));
+            }
+
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+
+    fn set_content_encoding(headers: &mut HeaderMap, encoding: CacheEncodingValue) {
+        headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.to_str()),
+        );
+
+        ProxyHeader::weaken_etag(headers);
+    }
+
+    fn dispatch_ranged(
+        range: Option<ProxyRangeRequest>,
+        response: ProxyServeResponseFuture,
+    ) -> ProxyServeResponseFuture {
+        // Not a range request? Serve response as-is
+        let range = match range {
+            Some(range) => range,
+            None => return response,
+        };
+
+        Box::pin(async move {
+            let (mut parts, body) = response.await?.into_parts();
+
+            let body_bytes = body
+                .collect()
+                .await
+                .map_err(|err| -> ProxyServeError { Box::new(err) })?
+                .to_bytes();
+
+            let (status, body_bytes) =
+                ProxyRange::slice(&range, parts.status, &mut parts.headers, body_bytes);
+
+            if status != parts.status {
+                debug!("sliced response for range request with status: {}", status);
+
+                parts.status = status;
+
+                ProxyHeader::set_content_length(
+                    &mut parts.headers,
+                    &Method::GET,
+                    &status,
+                    body_bytes.len(),
+                );
+            }
+
+            Ok(Response::from_parts(parts, Full::new(body_bytes)))
         })
     }
 
@@ -127,27 +389,48 @@ impl ProxyServe {
         ns: &str,
         method: &Method,
         headers: &HeaderMap,
-    ) -> Result<Result<(String, Option<String>), ()>, ()> {
+    ) -> Result<ProxyServeFetchResult, ()> {
         let header_if_none_match = headers
             .get(header::IF_NONE_MATCH)
             .and_then(|value| value.to_str().ok())
             .map(|value| value.to_owned());
 
+        // Notice: 'If-Modified-Since' only applies to 'GET' and 'HEAD' \
+        //   requests (RFC 9110, section 13.1.3)
+        let header_if_modified_since = match *method {
+            Method::GET | Method::HEAD => headers
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned()),
+            _ => None,
+        };
+
         let ns_string = ns.to_string();
 
-        match CacheRead::acquire_meta(shard, ns, method).await {
-            Ok(Ok((fingerprint, is_body_compressed))) => {
+        let request_policy = Self::acquire_request_policy(shard, headers);
+
+        let encoding = Self::negotiate_encoding(method, headers);
+
+        match CacheRead::acquire_meta(shard, ns, method, &request_policy, headers).await {
+            Ok(Ok(meta)) => {
                 debug!(
                     "got fingerprint for cached data = {} on ns = {}",
-                    &fingerprint, &ns_string
+                    &meta.fingerprint, &ns_string
                 );
 
-                // Check if not modified?
-                let isnt_modified = match &header_if_none_match {
-                    Some(if_none_match_value) => {
-                        ProxyHeader::check_if_none_match(if_none_match_value, &fingerprint)
+                // Check if not modified? ('If-Modified-Since' is ignored if \
+                //   'If-None-Match' is set)
+                let isnt_modified = match (&header_if_none_match, &header_if_modified_since) {
+                    (Some(if_none_match_value), _) => {
+                        ProxyHeader::check_if_none_match(if_none_match_value, &meta.fingerprint)
                     }
-                    None => false,
+                    (None, Some(if_modified_since_value)) => meta
+                        .modified
+                        .map(|modified| {
+                            ProxyHeader::check_if_modified_since(if_modified_since_value, modified)
+                        })
+                        .unwrap_or(false),
+                    (None, None) => false,
                 };
 
                 debug!(
@@ -155,50 +438,112 @@ impl ProxyServe {
                     &isnt_modified, &ns_string
                 );
 
-                Self::fetch_cached_data_body(
-                    ns_string,
-                    fingerprint,
-                    !isnt_modified,
-                    is_body_compressed,
-                )
-                .await
+                Self::fetch_cached_data_body(meta, !isnt_modified, encoding).await
             }
-            Ok(Err(_)) => Ok(Err(())),
+            Ok(Err(CacheReadError::Expired(meta))) => {
+                debug!("got expired cached data on ns = {}", &ns_string);
+
+                // Cache is stale, though it may still be served as a fallback
+                Ok(Err(Some(*meta)))
+            }
+            Ok(Err(_)) => Ok(Err(None)),
             Err(_) => {
                 error!("failed fetching cached data meta");
 
-                Ok(Err(()))
+                Ok(Err(None))
             }
         }
     }
 
+    fn acquire_request_policy(shard: u8, headers: &HeaderMap) -> CachePolicyRequest {
+        // Only honor client caching policy if the shard trusts its callers
+        let honor_request_cache_control = APP_CONF
+            .proxy
+            .find_shard(shard)
+            .map(|shard_config| shard_config.honor_request_cache_control)
+            .unwrap_or(false);
+
+        if honor_request_cache_control == true {
+            CachePolicy::from_request(headers)
+        } else {
+            CachePolicyRequest::default()
+        }
+    }
+
     async fn fetch_cached_data_body(
-        ns: String,
-        fingerprint: String,
+        meta: CacheStoreMeta,
         do_acquire_body: bool,
-        is_body_compressed: bool,
-    ) -> Result<Result<(String, Option<String>), ()>, ()> {
+        encoding: Option<CacheEncodingValue>,
+    ) -> Result<ProxyServeFetchResult, ()> {
+        let (key, fingerprint) = (meta.key, meta.fingerprint);
+
         // Do not acquire body? (not modified)
         if do_acquire_body == false {
             return Ok(Ok((fingerprint, None)));
         }
 
-        // Will acquire body (modified)
-        match CacheRead::acquire_body(&ns, is_body_compressed).await {
-            Ok(Ok(body)) => Ok(Ok((fingerprint, body))),
+        // Will acquire envelope (modified)
+        match CacheRead::acquire_envelope(&key, meta.compressed, encoding).await {
+            Ok(Ok(Some(mut envelope))) => {
+                envelope.modified = meta.modified;
+
+                // Client accepts an encoding, though no encoded body is \
+                //   stored yet? Encode the stored body, and store it.
+                let envelope = match encoding {
+                    Some(encoding) if envelope.encoding.is_none() => {
+                        Self::encode_cached_envelope(key, &fingerprint, encoding, envelope)
+                    }
+                    _ => envelope,
+                };
+
+                Ok(Ok((fingerprint, Some(envelope))))
+            }
+            Ok(Ok(None)) => Ok(Ok((fingerprint, None))),
             Ok(Err(_)) => {
                 error!("failed fetching cached data body");
 
-                Ok(Err(()))
+                Ok(Err(None))
             }
             Err(_) => {
                 error!("failed fetching cached data body");
 
-                Ok(Err(()))
+                Ok(Err(None))
             }
         }
     }
 
+    fn encode_cached_envelope(
+        key: String,
+        fingerprint: &str,
+        encoding: CacheEncodingValue,
+        mut envelope: CacheEnvelope,
+    ) -> CacheEnvelope {
+        if envelope.body.len() < APP_CONF.proxy.compress_responses_above_bytes
+            || CacheEncoding::is_compressible(&envelope.status, &envelope.header_map()) == false
+        {
+            return envelope;
+        }
+
+        match CacheEncoding::encode(encoding, &envelope.body) {
+            Ok(encoded_bytes) => {
+                CacheWrite::save_encoded(
+                    key,
+                    fingerprint.to_owned(),
+                    encoding,
+                    encoded_bytes.clone(),
+                );
+
+                envelope.body = encoded_bytes;
+                envelope.encoding = Some(encoding);
+            }
+            Err(err) => {
+                error!("could not encode cached data body because: {}", err);
+            }
+        }
+
+        envelope
+    }
+
     async fn queue_tunnel_over_proxy(
         shard: u8,
         ns: String,
@@ -206,9 +551,9 @@ impl ProxyServe {
         auth_hash: String,
         method: Method,
         uri: Uri,
-        version: Version,
         headers: HeaderMap,
         body: Incoming,
+        stale: Option<CacheStoreMeta>,
     ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
         debug!("queue request for tunnelling with ns = {}", ns);
 
@@ -221,8 +566,9 @@ impl ProxyServe {
         //   performed in parallel since there is no benefit in locking here.
         // Important #2: the lock guard HAS TO BE RETURNED so that it is not \
         //   immediately dropped. We need the drop to occur when this method \
-        //   returns. It is NOT used, ONLY returned for ownership reasons.
-        let _lock_guard = if APP_CONF.proxy.lock_tunnel_path == true
+        //   returns. It is also used to tell queued requests whether the API \
+        //   failed to respond to the previous lock holder.
+        let mut lock_guard = if APP_CONF.proxy.lock_tunnel_path == true
             && CacheCheck::from_request(&method) == true
         {
             // Acquire slowlog vector (start time and slowlog threshold, if \
@@ -275,8 +621,10 @@ impl ProxyServe {
                     );
 
                     return Self::dispatch_cached(
-                        shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
-                        value.0, value.1,
+                        method,
+                        value.0,
+                        value.1,
+                        HeaderBloomStatusValue::Hit,
                     )
                     .await;
                 } else {
@@ -285,6 +633,20 @@ impl ProxyServe {
                         ns
                     );
                 }
+
+                // API failed to respond to the previous lock holder? Serve \
+                //   stale cache right away (if any), rather than having each \
+                //   queued request wait on the down API in turn.
+                if let Err(Some(ref stale_meta)) = fetch_result_recheck {
+                    if lock_guard.has_failed() == true {
+                        if let Some(stale_result) =
+                            Self::dispatch_stale(&method, stale_meta, HeaderBloomStatusValue::Stale)
+                                .await
+                        {
+                            return stale_result;
+                        }
+                    }
+                }
             }
 
             Some(lock_guard)
@@ -298,10 +660,65 @@ impl ProxyServe {
         };
 
         // Dispatch request to the downstream API server
-        Self::dispatch_tunnel(
-            shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
+        let tunnel_result = Self::dispatch_tunnel(
+            shard,
+            ns,
+            ns_mask,
+            auth_hash,
+            method,
+            uri,
+            headers,
+            Some(body),
+            stale,
         )
-        .await
+        .await;
+
+        // Mark whether the API failed to respond, for queued requests to \
+        //   know about it once they obtain the lock
+        if let Some(ref mut lock_guard_value) = lock_guard {
+            lock_guard_value.set_failed(Self::check_tunnel_failed(&tunnel_result));
+        }
+
+        tunnel_result
+    }
+
+    fn check_tunnel_failed(tunnel_result: &Result<Response<Full<Bytes>>, ProxyServeError>) -> bool {
+        match tunnel_result {
+            Ok(response) => {
+                let bloom_status = response.headers().get(HeaderBloomStatus::header_name());
+
+                bloom_status
+                    == Some(&HeaderBloomStatus(HeaderBloomStatusValue::Offline).to_header_value())
+                    || bloom_status
+                        == Some(&HeaderBloomStatus(HeaderBloomStatusValue::Stale).to_header_value())
+            }
+            Err(_) => true,
+        }
+    }
+
+    fn spawn_refresh<F>(ns: String, refresh: F)
+    where
+        F: Future<Output = Result<Response<Full<Bytes>>, ProxyServeError>> + Send + 'static,
+    {
+        // Refresh only if no request is already being tunneled for this \
+        //   cache namespace (as it will refresh the cache anyway), which \
+        //   also ensures a single background refresh runs at a time.
+        // Notice: the refresh future does nothing until it gets polled, \
+        //   hence it costs nothing to drop if a refresh is already running.
+        if let Some(mut lock_guard) = ProxyLock::try_acquire(&ns) {
+            debug!("refreshing stale cached data in background for ns = {}", ns);
+
+            tokio::spawn(async move {
+                let tunnel_result = refresh.await;
+
+                lock_guard.set_failed(Self::check_tunnel_failed(&tunnel_result));
+            });
+        } else {
+            debug!(
+                "stale cached data is already being refreshed for ns = {}",
+                ns
+            );
+        }
     }
 
     async fn dispatch_tunnel(
@@ -311,27 +728,73 @@ impl ProxyServe {
         auth_hash: String,
         method: Method,
         uri: Uri,
-        version: Version,
         headers: HeaderMap,
-        body: Incoming,
+        body: Option<Incoming>,
+        stale: Option<CacheStoreMeta>,
     ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
         // Clone method value for closures. Sadly, it looks like Rust borrow \
         //   checker doesnt discriminate properly on this check.
         let method_success = method.to_owned();
         let method_failure = method.to_owned();
 
-        let tunnel_result: Result<_, ProxyServeError> =
-            ProxyTunnel::run(&method, &uri, &headers, body, shard).await;
+        let tunnel_result: Result<_, ProxyServeError> = ProxyTunnel::run(
+            &method,
+            &uri,
+            &headers,
+            body,
+            stale.as_ref().map(|stale_meta| &stale_meta.validators),
+            shard,
+            Some(&ns),
+        )
+        .await;
 
         match tunnel_result {
             Ok(tunnel_res) => {
+                // API server tells that stale cache is not modified? Refresh \
+                //   it and serve it (the stored body does not need to be \
+                //   fetched again)
+                if tunnel_res.status() == StatusCode::NOT_MODIFIED {
+                    if let Some(ref stale_meta) = stale {
+                        if stale_meta.validators.is_empty() == false {
+                            return match Self::dispatch_revalidated(
+                                shard,
+                                &method_success,
+                                stale_meta,
+                                &headers,
+                                tunnel_res.headers().to_owned(),
+                            )
+                            .await
+                            {
+                                Some(revalidated_result) => revalidated_result,
+                                None => Self::dispatch_failure(&method_failure).await,
+                            };
+                        }
+                    }
+                }
+
+                // API server failed to process the request? Serve stale cache \
+                //   instead (if any)
+                if tunnel_res.status().is_server_error() == true {
+                    if let Some(ref stale_meta) = stale {
+                        if let Some(stale_result) = Self::dispatch_stale(
+                            &method_failure,
+                            stale_meta,
+                            HeaderBloomStatusValue::Stale,
+                        )
+                        .await
+                        {
+                            return stale_result;
+                        }
+                    }
+                }
+
                 let write_result = CacheWrite::save(
                     ns,
                     ns_mask,
                     auth_hash,
                     shard,
                     method,
-                    version,
+                    headers,
                     tunnel_res.status(),
                     tunnel_res.headers().to_owned(),
                     tunnel_res.into_body(),
@@ -340,19 +803,23 @@ impl ProxyServe {
 
                 match write_result {
                     Ok(mut result) => match result.body {
-                        Ok(body_string) => {
+                        Ok(body_bytes) => {
+                            if let Some(modified) = result.modified {
+                                ProxyHeader::set_last_modified(&mut result.headers, modified);
+                            }
+
                             Self::dispatch_fetched(
                                 &method_success,
                                 &result.status,
                                 result.headers,
                                 HeaderBloomStatusValue::Miss,
-                                body_string,
+                                body_bytes,
                                 result.fingerprint,
                             )
                             .await
                         }
-                        Err(body_string_values) => match body_string_values {
-                            Some(body_string) => {
+                        Err(body_bytes_values) => match body_bytes_values {
+                            Some(body_bytes) => {
                                 // Enforce clean headers, as usually they get \
                                 //   cleaned from cache writer
                                 HeaderJanitor::clean(&mut result.headers);
@@ -362,7 +829,7 @@ impl ProxyServe {
                                     &result.status,
                                     result.headers,
                                     HeaderBloomStatusValue::Direct,
-                                    body_string,
+                                    body_bytes,
                                     result.fingerprint,
                                 )
                                 .await
@@ -373,77 +840,194 @@ impl ProxyServe {
                     Err(_) => Self::dispatch_failure(&method_failure).await,
                 }
             }
-            Err(_) => Self::dispatch_failure(&method_failure).await,
+            Err(_) => {
+                // API server is unreachable? Serve stale cache instead (if any)
+                if let Some(ref stale_meta) = stale {
+                    if let Some(stale_result) = Self::dispatch_stale(
+                        &method_failure,
+                        stale_meta,
+                        HeaderBloomStatusValue::Stale,
+                    )
+                    .await
+                    {
+                        return stale_result;
+                    }
+                }
+
+                Self::dispatch_failure(&method_failure).await
+            }
         }
     }
 
-    async fn dispatch_cached(
+    async fn dispatch_direct(
         shard: u8,
-        ns: String,
-        ns_mask: String,
-        auth_hash: String,
         method: Method,
-        req_uri: Uri,
-        req_version: Version,
-        req_headers: HeaderMap,
-        req_body: Incoming,
-        res_fingerprint: String,
-        res_string: Option<String>,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Incoming,
     ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
-        // Response modified? (non-empty body)
-        if let Some(res_string_value) = res_string {
-            let mut headers = [httparse::EMPTY_HEADER; CACHED_PARSE_MAX_HEADERS];
-            let mut res = httparse::Response::new(&mut headers);
-
-            // Split headers from body
-            let body = Self::parse_response_body(&res_string_value);
-
-            match res.parse(res_string_value.as_bytes()) {
-                Ok(_) => {
-                    // Process cached status
-                    let code = res.code.unwrap_or(500u16);
-                    let status =
-                        StatusCode::from_u16(code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-
-                    // Process cached headers
-                    let mut headers = HeaderMap::new();
-
-                    for header in res.headers {
-                        if let (Ok(header_name), Ok(header_value)) = (
-                            HeaderName::from_bytes(header.name.as_bytes()),
-                            HeaderValue::from_bytes(header.value),
-                        ) {
-                            headers.insert(header_name, header_value);
-                        }
-                    }
+        match ProxyTunnel::run(&method, &uri, &headers, Some(body), None, shard, None).await {
+            Ok(tunnel_res) => {
+                let (tunnel_parts, tunnel_body) = tunnel_res.into_parts();
 
-                    ProxyHeader::set_etag(&mut headers, &res_fingerprint);
+                let body_bytes = tunnel_body
+                    .collect()
+                    .await
+                    .map_err(|err| -> ProxyServeError { Box::new(err) })?
+                    .to_bytes();
 
-                    headers.insert(
-                        HeaderBloomStatus::header_name(),
-                        HeaderBloomStatus(HeaderBloomStatusValue::Hit).to_header_value(),
-                    );
+                let mut res_headers = tunnel_parts.headers;
 
-                    // Serve cached response
-                    Self::respond(method, status, headers, body).await
-                }
-                Err(err) => {
-                    error!("failed parsing cached response: {}", err);
+                HeaderJanitor::clean(&mut res_headers);
 
-                    Self::dispatch_tunnel(
-                        shard,
-                        ns,
-                        ns_mask,
-                        auth_hash,
-                        method,
-                        req_uri,
-                        req_version,
-                        req_headers,
-                        req_body,
+                Self::dispatch_fetched(
+                    &method,
+                    &tunnel_parts.status,
+                    res_headers,
+                    HeaderBloomStatusValue::Direct,
+                    body_bytes,
+                    None,
+                )
+                .await
+            }
+            Err(_) => Self::dispatch_failure(&method).await,
+        }
+    }
+
+    async fn dispatch_stale(
+        method: &Method,
+        stale_meta: &CacheStoreMeta,
+        bloom_status: HeaderBloomStatusValue,
+    ) -> Option<Result<Response<Full<Bytes>>, ProxyServeError>> {
+        // Notice: stale cached data is read from the key it was acquired \
+        //   from, which differs from the route key if the route varies.
+        let key = &stale_meta.key;
+
+        debug!("attempting to serve stale cached data for key = {}", key);
+
+        match CacheRead::acquire_envelope(key, stale_meta.compressed, None).await {
+            Ok(Ok(Some(mut envelope))) => {
+                envelope.modified = stale_meta.modified;
+
+                Some(
+                    Self::dispatch_cached(
+                        method.clone(),
+                        stale_meta.fingerprint.to_owned(),
+                        Some(envelope),
+                        bloom_status,
                     )
-                    .await
-                }
+                    .await,
+                )
+            }
+            _ => {
+                warn!("could not serve stale cached data for key = {}", key);
+
+                None
+            }
+        }
+    }
+
+    async fn dispatch_revalidated(
+        shard: u8,
+        method: &Method,
+        stale_meta: &CacheStoreMeta,
+        headers: &HeaderMap,
+        res_headers: HeaderMap,
+    ) -> Option<Result<Response<Full<Bytes>>, ProxyServeError>> {
+        let key = &stale_meta.key;
+
+        debug!("stale cached data is not modified for key = {}", key);
+
+        match CacheRead::acquire_envelope(key, stale_meta.compressed, None).await {
+            Ok(Ok(Some(mut envelope))) => {
+                envelope.modified = stale_meta.modified;
+
+                let (fingerprint, envelope) =
+                    CacheWrite::refresh(key.to_owned(), shard, stale_meta, envelope, res_headers)
+                        .await;
+
+                // Check if not modified for client?
+                let isnt_modified = headers
+                    .get(header::IF_NONE_MATCH)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| ProxyHeader::check_if_none_match(value, &fingerprint))
+                    .unwrap_or(false);
+
+                Some(
+                    Self::dispatch_cached(
+                        method.clone(),
+                        fingerprint,
+                        if isnt_modified == true {
+                            None
+                        } else {
+                            Some(envelope)
+                        },
+                        HeaderBloomStatusValue::Miss,
+                    )
+                    .await,
+                )
+            }
+            _ => {
+                warn!("could not refresh stale cached data for key = {}", key);
+
+                None
             }
+        }
+    }
+
+    async fn dispatch_cached(
+        method: Method,
+        res_fingerprint: String,
+        res_envelope: Option<CacheEnvelope>,
+        bloom_status: HeaderBloomStatusValue,
+    ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
+        // Response modified? (non-empty envelope)
+        if let Some(res_envelope_value) = res_envelope {
+            // Process cached headers
+            let mut headers = Self::generate_cached_headers(res_envelope_value.headers);
+
+            // Enforce clean headers (entries stored by older Bloom versions \
+            //   may still hold hop-by-hop headers), and frame the replayed \
+            //   body with its actual length
+            // Notice: responses to 'HEAD' requests are framed as the cached \
+            //   'GET' response they are served from.
+            HeaderJanitor::clean(&mut headers);
+
+            ProxyHeader::set_content_length(
+                &mut headers,
+                if method == Method::HEAD {
+                    &Method::GET
+                } else {
+                    &method
+                },
+                &res_envelope_value.status,
+                res_envelope_value.body.len(),
+            );
+
+            ProxyHeader::set_etag(&mut headers, &res_fingerprint);
+
+            if let Some(modified) = res_envelope_value.modified {
+                ProxyHeader::set_last_modified(&mut headers, modified);
+            }
+
+            // Serve encoded body? (as stored in cache)
+            if let Some(encoding) = res_envelope_value.encoding {
+                Self::set_content_encoding(&mut headers, encoding);
+            }
+
+            headers.insert(
+                HeaderBloomStatus::header_name(),
+                HeaderBloomStatus(bloom_status).to_header_value(),
+            );
+
+            // Serve cached response
+            Self::respond(
+                method,
+                res_envelope_value.status,
+                headers,
+                res_envelope_value.body,
+            )
+            .await
         } else {
             // Response not modified for client, process non-modified + cached headers
             let mut headers = HeaderMap::new();
@@ -452,11 +1036,11 @@ impl ProxyServe {
 
             headers.insert(
                 HeaderBloomStatus::header_name(),
-                HeaderBloomStatus(HeaderBloomStatusValue::Hit).to_header_value(),
+                HeaderBloomStatus(bloom_status).to_header_value(),
             );
 
             // Serve non-modified response
-            Self::respond(method, StatusCode::NOT_MODIFIED, headers, String::from("")).await
+            Self::respond(method, StatusCode::NOT_MODIFIED, headers, Bytes::new()).await
         }
     }
 
@@ -465,7 +1049,7 @@ impl ProxyServe {
         status: &StatusCode,
         mut headers: HeaderMap,
         bloom_status: HeaderBloomStatusValue,
-        body_string: String,
+        body_bytes: Bytes,
         fingerprint: Option<String>,
     ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
         // Process ETag for content?
@@ -478,43 +1062,60 @@ impl ProxyServe {
             HeaderBloomStatus(bloom_status).to_header_value(),
         );
 
-        Self::respond(method.clone(), *status, headers, body_string).await
+        Self::respond(method.clone(), *status, headers, body_bytes).await
     }
 
-    async fn dispatch_failure(method: &Method) -> Result<Response<Full<Bytes>>, ProxyServeError> {
-        let status = StatusCode::BAD_GATEWAY;
+    async fn dispatch_uncached(method: &Method) -> Result<Response<Full<Bytes>>, ProxyServeError> {
+        let status = StatusCode::GATEWAY_TIMEOUT;
 
         let mut headers = HeaderMap::new();
 
         headers.insert(
             HeaderBloomStatus::header_name(),
-            HeaderBloomStatus(HeaderBloomStatusValue::Offline).to_header_value(),
+            HeaderBloomStatus(HeaderBloomStatusValue::Miss).to_header_value(),
         );
 
-        Self::respond(method.clone(), status, headers, format!("{}", status)).await
+        Self::respond(
+            method.clone(),
+            status,
+            headers,
+            Bytes::from(format!("{}", status)),
+        )
+        .await
     }
 
-    fn parse_response_body(res_string_value: &str) -> String {
-        let (mut body, mut is_last_line_empty) = (String::new(), false);
+    async fn dispatch_failure(method: &Method) -> Result<Response<Full<Bytes>>, ProxyServeError> {
+        let status = StatusCode::BAD_GATEWAY;
+
+        let mut headers = HeaderMap::new();
 
-        // Scan response lines
-        let lines = res_string_value.lines().with_position();
+        headers.insert(
+            HeaderBloomStatus::header_name(),
+            HeaderBloomStatus(HeaderBloomStatusValue::Offline).to_header_value(),
+        );
 
-        for (position, line) in lines {
-            if body.is_empty() == false || is_last_line_empty == true {
-                // Append line to body
-                body.push_str(line);
+        Self::respond(
+            method.clone(),
+            status,
+            headers,
+            Bytes::from(format!("{}", status)),
+        )
+        .await
+    }
 
-                // Append line feed character?
-                if let Position::First | Position::Middle = position {
-                    body.push_str(LINE_FEED);
-                }
-            }
+    fn generate_cached_headers(cached_headers: Vec<(HeaderName, HeaderValue)>) -> HeaderMap {
+        let mut headers = HeaderMap::with_capacity(cached_headers.len());
 
-            is_last_line_empty = line.is_empty();
+        // Append all header occurrences (headers such as 'Link' or 'Vary' \
+        //   may be repeated, and must all be replayed)
+        // Notice: occurrences are stored grouped by header name (as in \
+        //   'HeaderMap'), so the order is kept for each header name, though \
+        //   not across different header names.
+        for (header_name, header_value) in cached_headers {
+            headers.append(header_name, header_value);
         }
 
-        body
+        headers
     }
 
     fn make_proxy_error(msg: &'static str) -> ProxyServeError {
@@ -525,11 +1126,11 @@ impl ProxyServe {
         method: Method,
         status: StatusCode,
         headers: HeaderMap,
-        body_string: String,
+        body_bytes: Bytes,
     ) -> Result<Response<Full<Bytes>>, ProxyServeError> {
         let body = match method {
             Method::GET | Method::POST | Method::PATCH | Method::PUT | Method::DELETE => {
-                Full::new(Bytes::from(body_string))
+                Full::new(body_bytes)
             }
             _ => Full::new(Bytes::new()),
         };
@@ -547,14 +1148,46 @@ impl ProxyServe {
 mod tests {
     use super::*;
 
+    use crate::cache::envelope::CacheEnvelope;
+
     #[test]
-    fn it_parses_response_body() {
-        let body = "2022-10-03";
-        let headers =
-            "Content-Type: text/plain; charset=utf-8\nServer: Kestrel\nTransfer-Encoding: chunked";
+    fn it_replays_all_cached_headers() {
+        let mut upstream_headers = HeaderMap::new();
+
+        upstream_headers.append(header::LINK, HeaderValue::from_static("</a>; rel=preload"));
+        upstream_headers.append(header::LINK, HeaderValue::from_static("</b>; rel=preload"));
+        upstream_headers.append(header::VARY, HeaderValue::from_static("Accept"));
+        upstream_headers.append(header::VARY, HeaderValue::from_static("Accept-Language"));
+
+        for index in 0..150 {
+            upstream_headers.append(
+                HeaderName::from_bytes(format!("x-header-{}", index).as_bytes()).unwrap(),
+                HeaderValue::from(index),
+            );
+        }
+
+        let envelope = CacheEnvelope::new(StatusCode::OK, &upstream_headers, Bytes::new());
 
-        let response_string = format!("{headers}\n\n{body}");
+        let cached_envelope = CacheEnvelope::decode(
+            &envelope.encode_status(),
+            &envelope.encode_headers(),
+            envelope.body.clone(),
+        )
+        .unwrap();
+
+        let headers = ProxyServe::generate_cached_headers(cached_envelope.headers);
 
-        assert_eq!(body, ProxyServe::parse_response_body(&response_string));
+        assert_eq!(headers.len(), upstream_headers.len());
+
+        assert_eq!(
+            headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_bytes()))
+                .collect::<Vec<_>>(),
+            upstream_headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_bytes()))
+                .collect::<Vec<_>>()
+        );
     }
 }
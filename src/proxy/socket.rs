@@ -0,0 +1,51 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+use tower_service::Service;
+
+#[derive(Clone)]
+pub struct ProxySocketConnector {
+    path: Arc<PathBuf>,
+}
+
+type ProxySocketConnectorFuture =
+    Pin<Box<dyn Future<Output = Result<TokioIo<UnixStream>, io::Error>> + Send>>;
+
+impl ProxySocketConnector {
+    pub fn new(path: PathBuf) -> Self {
+        ProxySocketConnector {
+            path: Arc::new(path),
+        }
+    }
+}
+
+impl Service<Uri> for ProxySocketConnector {
+    type Response = TokioIo<UnixStream>;
+    type Error = io::Error;
+    type Future = ProxySocketConnectorFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        // Notice: the request URI is ignored, as all requests to a shard \
+        //   bound to a socket get sent to this socket.
+        let path = self.path.clone();
+
+        Box::pin(async move { UnixStream::connect(path.as_path()).await.map(TokioIo::new) })
+    }
+}
@@ -0,0 +1,281 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+use crate::config::config::ConfigProxyShardTls;
+use crate::server::tls::ServerTls;
+
+pub struct ProxyTls;
+
+#[derive(Debug)]
+struct ProxyTlsInsecureVerifier(Arc<CryptoProvider>);
+
+impl ProxyTls {
+    pub fn make_client_config(tls: Option<&ConfigProxyShardTls>) -> Result<ClientConfig, ()> {
+        let provider = Arc::new(ring::default_provider());
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| error!("could not configure tls protocols because: {}", err))?;
+
+        // Notice: certificates are still required to be signed with their \
+        //   own key, though they are not required to be trusted nor valid.
+        let builder = match tls {
+            Some(tls) if tls.insecure_skip_verify == true => {
+                warn!("tls certificates of shard will not be verified (insecure)");
+
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(ProxyTlsInsecureVerifier(provider)))
+            }
+            _ => builder.with_root_certificates(Self::make_roots(
+                tls.and_then(|tls| tls.ca_bundle.as_deref()),
+            )?),
+        };
+
+        // Present a client certificate to the API? (mutual TLS)
+        let client_auth = tls.map(|tls| {
+            (
+                tls.client_certificate.as_deref(),
+                tls.client_private_key.as_deref(),
+            )
+        });
+
+        match client_auth {
+            Some((Some(client_certificate), Some(client_private_key))) => builder
+                .with_client_auth_cert(
+                    ServerTls::load_certificates(client_certificate)?,
+                    ServerTls::load_private_key(client_private_key)?,
+                )
+                .map_err(|err| error!("could not use tls client certificate because: {}", err)),
+            Some((None, None)) | None => Ok(builder.with_no_client_auth()),
+            Some(_) => {
+                error!("tls client certificate and private key must be set together");
+
+                Err(())
+            }
+        }
+    }
+
+    pub fn acquire_server_name(
+        tls: Option<&ConfigProxyShardTls>,
+    ) -> Result<Option<ServerName<'static>>, ()> {
+        match tls.and_then(|tls| tls.server_name.as_ref()) {
+            Some(server_name) => ServerName::try_from(server_name.to_owned())
+                .map(Some)
+                .map_err(|err| {
+                    error!("invalid tls server name: {} because: {}", server_name, err);
+                }),
+            None => Ok(None),
+        }
+    }
+
+    fn make_roots(ca_bundle: Option<&Path>) -> Result<RootCertStore, ()> {
+        match ca_bundle {
+            Some(ca_bundle) => {
+                let mut roots = RootCertStore::empty();
+
+                for certificate in ServerTls::load_certificates(ca_bundle)? {
+                    roots.add(certificate).map_err(|err| {
+                        error!("could not add tls ca: {:?} because: {}", ca_bundle, err);
+                    })?;
+                }
+
+                Ok(roots)
+            }
+            None => Ok(RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }),
+        }
+    }
+}
+
+impl ServerCertVerifier for ProxyTlsInsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use tokio_rustls::TlsConnector;
+
+    use crate::config::config::ConfigServerTls;
+    use crate::server::tls::tests::{handshake, make_authority, make_signed, write_files};
+
+    fn make_shard_tls() -> ConfigProxyShardTls {
+        ConfigProxyShardTls {
+            ca_bundle: None,
+            client_certificate: None,
+            client_private_key: None,
+            server_name: None,
+            insecure_skip_verify: false,
+        }
+    }
+
+    fn make_connector(tls: &ConfigProxyShardTls) -> TlsConnector {
+        TlsConnector::from(Arc::new(ProxyTls::make_client_config(Some(tls)).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn it_verifies_server_certificate() {
+        let authority = make_authority();
+        let (certificate, key) = make_signed(&authority, "localhost");
+
+        let directory = write_files(
+            "upstream",
+            &[
+                ("server.crt", &certificate),
+                ("server.key", &key),
+                ("ca.crt", &authority.pem),
+            ],
+        );
+
+        let acceptor = ServerTls::make_acceptor(&ConfigServerTls {
+            certificate: directory.join("server.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: None,
+            handshake_timeout_seconds: 10,
+        })
+        .unwrap();
+
+        let mut tls = make_shard_tls();
+
+        assert!(handshake(acceptor.clone(), make_connector(&tls))
+            .await
+            .is_err());
+
+        tls.insecure_skip_verify = true;
+
+        assert_eq!(
+            handshake(acceptor.clone(), make_connector(&tls)).await,
+            Ok(None)
+        );
+
+        tls.insecure_skip_verify = false;
+        tls.ca_bundle = Some(directory.join("ca.crt"));
+
+        assert_eq!(handshake(acceptor, make_connector(&tls)).await, Ok(None));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_presents_client_certificate() {
+        let authority = make_authority();
+        let client_authority = make_authority();
+
+        let (certificate, key) = make_signed(&authority, "localhost");
+        let (client_certificate, client_key) = make_signed(&client_authority, "bloom");
+
+        let directory = write_files(
+            "upstream-mutual",
+            &[
+                ("server.crt", &certificate),
+                ("server.key", &key),
+                ("ca.crt", &authority.pem),
+                ("client.crt", &client_certificate),
+                ("client.key", &client_key),
+                ("client-ca.crt", &client_authority.pem),
+            ],
+        );
+
+        let acceptor = ServerTls::make_acceptor(&ConfigServerTls {
+            certificate: directory.join("server.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: Some(directory.join("client-ca.crt")),
+            handshake_timeout_seconds: 10,
+        })
+        .unwrap();
+
+        let mut tls = make_shard_tls();
+
+        tls.ca_bundle = Some(directory.join("ca.crt"));
+
+        assert!(handshake(acceptor.clone(), make_connector(&tls))
+            .await
+            .is_err());
+
+        tls.client_certificate = Some(directory.join("client.crt"));
+
+        assert!(ProxyTls::make_client_config(Some(&tls)).is_err());
+
+        tls.client_private_key = Some(directory.join("client.key"));
+
+        assert_eq!(handshake(acceptor, make_connector(&tls)).await, Ok(None));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn it_acquires_server_name() {
+        let mut tls = make_shard_tls();
+
+        assert_eq!(ProxyTls::acquire_server_name(Some(&tls)), Ok(None));
+
+        tls.server_name = Some("api.internal".to_string());
+
+        assert_eq!(
+            ProxyTls::acquire_server_name(Some(&tls)),
+            Ok(Some(ServerName::try_from("api.internal").unwrap()))
+        );
+
+        tls.server_name = Some("not a name".to_string());
+
+        assert!(ProxyTls::acquire_server_name(Some(&tls)).is_err());
+    }
+}
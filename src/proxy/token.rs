@@ -0,0 +1,343 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::str::FromStr;
+
+use jsonwebtoken::jwk::{JwkSet, PublicKeyUse};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Header, Validation};
+use serde_json::Value;
+
+use crate::config::config::ConfigProxyShardJwt;
+use crate::APP_CONF;
+
+pub struct ProxyTokenBuilder;
+pub struct ProxyToken;
+
+pub struct ProxyTokenKeyring {
+    secret: Option<DecodingKey>,
+    keys: Vec<ProxyTokenKey>,
+    insecure: bool,
+}
+
+struct ProxyTokenKey {
+    id: Option<String>,
+    algorithm: Option<Algorithm>,
+    key: DecodingKey,
+}
+
+pub type ProxyTokenKeyrings = HashMap<u8, ProxyTokenKeyring>;
+
+impl ProxyTokenBuilder {
+    pub fn new() -> ProxyTokenKeyrings {
+        // Notice: keys are loaded once at startup, so that a missing or \
+        //   invalid JWKS file is reported right away, and not on each request.
+        APP_CONF
+            .proxy
+            .shard
+            .iter()
+            .filter_map(|shard_config| {
+                shard_config
+                    .jwt
+                    .as_ref()
+                    .map(|jwt| (shard_config.shard, ProxyTokenKeyring::from_config(jwt)))
+            })
+            .collect()
+    }
+}
+
+impl ProxyTokenKeyring {
+    fn from_config(jwt: &ConfigProxyShardJwt) -> Self {
+        let keys = match jwt.jwks {
+            Some(ref jwks_path) => {
+                info!("loading jwt keys from jwks file: {:?}", jwks_path);
+
+                let jwks = fs::read_to_string(jwks_path)
+                    .unwrap_or_else(|_| panic!("could not read jwks file: {:?}", jwks_path));
+
+                Self::parse_jwks(&jwks)
+                    .unwrap_or_else(|_| panic!("invalid jwks file: {:?}", jwks_path))
+            }
+            None => Vec::new(),
+        };
+
+        // Notice: unverified tokens can be forged to impersonate any \
+        //   identity, and thus be served the cache of other users. Hence \
+        //   why this must be explicitly opted-in.
+        if jwt.secret.is_none() && jwt.jwks.is_none() {
+            if jwt.insecure_skip_verify == false {
+                panic!("jwt secret or jwks must be set (or insecure_skip_verify enabled)");
+            }
+
+            warn!("jwt tokens of shard will not be verified (insecure)");
+        }
+
+        ProxyTokenKeyring {
+            insecure: jwt.insecure_skip_verify,
+            secret: jwt
+                .secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            keys,
+        }
+    }
+
+    fn parse_jwks(jwks: &str) -> Result<Vec<ProxyTokenKey>, serde_json::Error> {
+        let jwks: JwkSet = serde_json::from_str(jwks)?;
+
+        Ok(jwks
+            .keys
+            .iter()
+            .filter(|jwk| jwk.common.public_key_use != Some(PublicKeyUse::Encryption))
+            .filter_map(|jwk| {
+                // Notice: keys meant for another purpose than signing (or \
+                //   that cannot be decoded) are skipped.
+                let algorithm = match jwk.common.key_algorithm {
+                    Some(key_algorithm) => {
+                        Some(Algorithm::from_str(&key_algorithm.to_string()).ok()?)
+                    }
+                    None => None,
+                };
+
+                Some(ProxyTokenKey {
+                    id: jwk.common.key_id.to_owned(),
+                    algorithm,
+                    key: DecodingKey::from_jwk(jwk).ok()?,
+                })
+            })
+            .collect())
+    }
+
+    fn find_key(&self, header: &Header) -> Option<&DecodingKey> {
+        // Match JWKS key on identifier (or pick the only key if the token \
+        //   does not tell), then fallback to the HS256 secret
+        let jwks_key = match header.kid {
+            Some(ref kid) => self.keys.iter().find(|key| key.id.as_ref() == Some(kid)),
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        };
+
+        match jwks_key {
+            Some(key) if key.algorithm.is_none() || key.algorithm == Some(header.alg) => {
+                Some(&key.key)
+            }
+            Some(_) => None,
+            None if header.alg == Algorithm::HS256 => self.secret.as_ref(),
+            None => None,
+        }
+    }
+}
+
+impl ProxyToken {
+    pub fn acquire_claim(token: &str, claim: &str, keyring: &ProxyTokenKeyring) -> Option<String> {
+        // Trim authorization scheme (eg. 'Bearer'), as JWTs cannot hold spaces
+        let token = token.trim().rsplit(' ').next().unwrap_or("");
+
+        let header = decode_header(token)
+            .map_err(|err| debug!("could not decode jwt header: {}", err))
+            .ok()?;
+
+        let mut validation = Validation::new(header.alg);
+
+        // Notice: expiry is checked strictly, though tokens may still not \
+        //   expire at all (their lifetime is up to the API)
+        validation.leeway = 0;
+        validation.validate_nbf = true;
+        validation.validate_aud = false;
+        validation.required_spec_claims = HashSet::new();
+
+        let unverified_key;
+
+        // Notice: if a secret or keys are configured, tokens are always \
+        //   verified (even if insecure verification skip is enabled)
+        let key = match keyring.find_key(&header) {
+            Some(key) => key,
+            None if keyring.insecure == true
+                && keyring.secret.is_none()
+                && keyring.keys.is_empty() == true =>
+            {
+                validation.insecure_disable_signature_validation();
+
+                unverified_key = DecodingKey::from_secret(&[]);
+
+                &unverified_key
+            }
+            None => {
+                debug!("could not find jwt key for kid: {:?}", header.kid);
+
+                return None;
+            }
+        };
+
+        let claims = decode::<Value>(token, key, &validation)
+            .map_err(|err| debug!("could not validate jwt: {}", err))
+            .ok()?
+            .claims;
+
+        // Acquire claim value (scalar values only)
+        match claims.get(claim)? {
+            Value::String(value) if value.is_empty() == false => Some(value.to_owned()),
+            Value::Number(value) => Some(value.to_string()),
+            Value::Bool(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey};
+    use serde_json::json;
+
+    fn make_keyring(secret: Option<&str>, jwks: Option<&str>) -> ProxyTokenKeyring {
+        ProxyTokenKeyring {
+            insecure: false,
+            secret: secret.map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            keys: jwks
+                .map(|jwks| ProxyTokenKeyring::parse_jwks(jwks).unwrap())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn make_token(kid: Option<&str>, secret: &str, claims: Value) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+
+        header.kid = kid.map(|kid| kid.to_string());
+
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_acquires_claim_from_unverified_token() {
+        let mut keyring = make_keyring(None, None);
+        let token = make_token(None, "any", json!({"sub": "user-1"}));
+
+        assert_eq!(ProxyToken::acquire_claim(&token, "sub", &keyring), None);
+
+        keyring.insecure = true;
+
+        let token = make_token(None, "any", json!({"sub": "user-1", "tenant_id": 42}));
+
+        assert_eq!(
+            ProxyToken::acquire_claim(&format!("Bearer {}", token), "sub", &keyring),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(&token, "tenant_id", &keyring),
+            Some("42".to_string())
+        );
+        assert_eq!(ProxyToken::acquire_claim(&token, "role", &keyring), None);
+        assert_eq!(
+            ProxyToken::acquire_claim("Bearer abc", "sub", &keyring),
+            None
+        );
+    }
+
+    #[test]
+    fn it_acquires_claim_from_verified_token() {
+        let keyring = make_keyring(Some("secret"), None);
+
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(None, "secret", json!({"sub": "user-1"})),
+                "sub",
+                &keyring
+            ),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(None, "other", json!({"sub": "user-1"})),
+                "sub",
+                &keyring
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn it_acquires_claim_from_jwks_verified_token() {
+        let keyring = make_keyring(
+            None,
+            Some(
+                r#"{"keys": [
+                    {"kty": "oct", "kid": "k1", "alg": "HS256", "k": "c2VjcmV0LTE"},
+                    {"kty": "oct", "kid": "k2", "k": "c2VjcmV0LTI"}
+                ]}"#,
+            ),
+        );
+
+        let claims = json!({"sub": "user-1"});
+
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(Some("k1"), "secret-1", claims.clone()),
+                "sub",
+                &keyring
+            ),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(Some("k2"), "secret-2", claims.clone()),
+                "sub",
+                &keyring
+            ),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(Some("k2"), "secret-1", claims.clone()),
+                "sub",
+                &keyring
+            ),
+            None
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(&make_token(None, "secret-1", claims), "sub", &keyring),
+            None
+        );
+    }
+
+    #[test]
+    fn it_rejects_expired_token() {
+        let keyring = make_keyring(Some("secret"), None);
+        let now = get_current_timestamp();
+
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(None, "secret", json!({"sub": "user-1", "exp": now + 60})),
+                "sub",
+                &keyring
+            ),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(None, "secret", json!({"sub": "user-1", "exp": now - 1})),
+                "sub",
+                &keyring
+            ),
+            None
+        );
+        assert_eq!(
+            ProxyToken::acquire_claim(
+                &make_token(None, "secret", json!({"sub": "user-1", "nbf": now + 60})),
+                "sub",
+                &keyring
+            ),
+            None
+        );
+    }
+}
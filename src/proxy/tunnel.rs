@@ -4,48 +4,128 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use std::borrow::Cow;
 use std::future::Future;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::time::Duration;
 
 use bytes::Bytes;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::body::Incoming;
-use hyper::header::HeaderMap;
+use hyper::header::{self, HeaderMap};
 use hyper::{Method, Request, Response, Uri};
+use hyper_rustls::{FixedServerNameResolver, HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::client::legacy::connect::HttpConnector;
-use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::{Client, ResponseFuture};
 use hyper_util::rt::TokioExecutor;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
 
+use super::balance::{ProxyBalance, ProxyBalanceGuard};
 use super::logger::ProxyLoggerRequest;
 use super::serve::ProxyServeError;
+use super::socket::ProxySocketConnector;
+use super::tls::ProxyTls;
+use crate::cache::route::CacheRoute;
+use crate::cache::store::CacheStoreValidators;
+use crate::header::janitor::HeaderJanitor;
 use crate::{APP_CONF, APP_PROXY_LOGGER};
 
 const MAX_SHARDS: u8 = 16;
+const MAX_BACKEND_WEIGHT: u32 = 100;
 const CLIENT_KEEP_ALIVE_TIMEOUT_SECONDS: u64 = 30;
 
 lazy_static! {
-    static ref SHARD_REGISTER: [Option<Uri>; MAX_SHARDS as usize] = map_shards();
+    static ref SHARD_REGISTER: [Option<ProxyTunnelShard>; MAX_SHARDS as usize] = map_shards();
 }
 
 thread_local! {
-    static TUNNEL_CLIENT: Client<HttpConnector, ProxyTunnelRequestBody> = make_client();
+    static TUNNEL_CLIENTS: Vec<Option<Vec<ProxyTunnelClient>>> = make_clients();
 }
 
 pub struct ProxyTunnel;
 
+struct ProxyTunnelShard {
+    backends: Vec<ProxyTunnelBackend>,
+    balance: ProxyBalance,
+    tls: ClientConfig,
+    server_name: Option<ServerName<'static>>,
+}
+
+struct ProxyTunnelBackend {
+    uri: Uri,
+    socket: Option<PathBuf>,
+}
+
+#[derive(Clone)]
+enum ProxyTunnelClient {
+    Network(Client<HttpsConnector<HttpConnector>, ProxyTunnelRequestBody>),
+    Socket(Client<ProxySocketConnector, ProxyTunnelRequestBody>),
+}
+
+struct ProxyTunnelTarget {
+    client: ProxyTunnelClient,
+
+    // Notice: held until the response is received, so that the request \
+    //   counts as in-flight on its backend until then.
+    _in_flight: ProxyBalanceGuard<'static>,
+}
+
 type ProxyTunnelRequestBody = BoxBody<Bytes, ProxyServeError>;
 
 type ProxyTunnelFuture =
     Pin<Box<dyn Future<Output = Result<Response<Incoming>, ProxyServeError>> + Send>>;
 
-fn make_client() -> Client<HttpConnector, ProxyTunnelRequestBody> {
-    Client::builder(TokioExecutor::new())
+fn make_clients() -> Vec<Option<Vec<ProxyTunnelClient>>> {
+    // Notice: each shard backend gets its own client, as TLS settings are \
+    //   per-shard and sockets are per-backend.
+    SHARD_REGISTER
+        .iter()
+        .map(|shard| {
+            shard.as_ref().map(|shard| {
+                shard
+                    .backends
+                    .iter()
+                    .map(|backend| make_client(shard, backend))
+                    .collect()
+            })
+        })
+        .collect()
+}
+
+fn make_client(shard: &ProxyTunnelShard, backend: &ProxyTunnelBackend) -> ProxyTunnelClient {
+    let mut builder = Client::builder(TokioExecutor::new());
+
+    builder
         .pool_idle_timeout(Duration::from_secs(CLIENT_KEEP_ALIVE_TIMEOUT_SECONDS))
-        .build(HttpConnector::new())
+        .http1_max_headers(APP_CONF.proxy.max_headers);
+
+    match backend.socket {
+        Some(ref socket) => {
+            ProxyTunnelClient::Socket(builder.build(ProxySocketConnector::new(socket.to_owned())))
+        }
+        None => ProxyTunnelClient::Network(builder.build(make_connector(shard))),
+    }
+}
+
+fn make_connector(shard: &ProxyTunnelShard) -> HttpsConnector<HttpConnector> {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(shard.tls.clone())
+        .https_or_http();
+
+    // Override name used for SNI and certificate verification? (if set)
+    let connector = match shard.server_name {
+        Some(ref server_name) => {
+            connector.with_server_name_resolver(FixedServerNameResolver::new(server_name.clone()))
+        }
+        None => connector,
+    };
+
+    connector.enable_http1().build()
 }
 
-fn map_shards() -> [Option<Uri>; MAX_SHARDS as usize] {
+fn map_shards() -> [Option<ProxyTunnelShard>; MAX_SHARDS as usize] {
     // Notice: this array cannot be initialized using the short format, as hyper::Uri doesnt \
     //   implement the Copy trait, hence the ugly hardcoded initialization vector w/ Nones.
     let mut shards = [
@@ -59,29 +139,99 @@ fn map_shards() -> [Option<Uri>; MAX_SHARDS as usize] {
             panic!("shard number overflows maximum of {} shards", MAX_SHARDS);
         }
 
-        // Store this shard
-        shards[shard.shard as usize] = Some(
-            format!("http://{}:{}", shard.host, shard.port)
-                .parse()
-                .expect("could not build shard uri"),
+        // Use shard host as its single backend if no backend is listed
+        let backends = if shard.backend.is_empty() == true {
+            vec![(&shard.host, shard.port, &shard.socket, 1)]
+        } else {
+            shard
+                .backend
+                .iter()
+                .map(|backend| (&backend.host, backend.port, &backend.socket, backend.weight))
+                .collect()
+        };
+
+        if backends
+            .iter()
+            .any(|(_, _, _, weight)| *weight < 1 || *weight > MAX_BACKEND_WEIGHT)
+        {
+            panic!(
+                "shard backend weight must be between 1 and {}",
+                MAX_BACKEND_WEIGHT
+            );
+        }
+
+        // Notice: backends are identified by their address for consistent \
+        //   hashing, so that reordering them does not move keys around.
+        let balance = ProxyBalance::new(
+            shard.balance,
+            &backends
+                .iter()
+                .map(|(host, port, socket, weight)| match socket {
+                    Some(socket) => (socket.to_string_lossy().into_owned(), *weight),
+                    None => (format!("{}:{}", host, port), *weight),
+                })
+                .collect::<Vec<_>>(),
         );
+
+        // Store this shard
+        shards[shard.shard as usize] = Some(ProxyTunnelShard {
+            backends: backends
+                .into_iter()
+                .map(|(host, port, socket, _)| ProxyTunnelBackend {
+                    uri: make_backend_uri(shard.scheme.to_str(), host, port, socket),
+                    socket: socket.to_owned(),
+                })
+                .collect(),
+            balance,
+            tls: ProxyTls::make_client_config(shard.tls.as_ref())
+                .expect("invalid shard tls configuration"),
+            server_name: ProxyTls::acquire_server_name(shard.tls.as_ref())
+                .expect("invalid shard tls server name"),
+        });
     }
 
     shards
 }
 
+fn make_backend_uri(scheme: &str, host: &str, port: u16, socket: &Option<PathBuf>) -> Uri {
+    // Notice: backends bound to a socket are always reached over plain \
+    //   HTTP, though their host and port are still used to build URIs.
+    let scheme = if socket.is_some() { "http" } else { scheme };
+
+    format!("{}://{}:{}", scheme, host, port)
+        .parse()
+        .expect("could not build shard uri")
+}
+
+impl ProxyTunnelClient {
+    fn request(&self, request: Request<ProxyTunnelRequestBody>) -> ResponseFuture {
+        match self {
+            ProxyTunnelClient::Network(client) => client.request(request),
+            ProxyTunnelClient::Socket(client) => client.request(request),
+        }
+    }
+}
+
 impl ProxyTunnel {
     pub fn run(
         method: &Method,
         uri: &Uri,
         headers: &HeaderMap,
-        body: Incoming,
+        body: Option<Incoming>,
+        validators: Option<&CacheStoreValidators>,
         shard: u8,
+        key: Option<&str>,
     ) -> ProxyTunnelFuture {
         if shard < MAX_SHARDS {
             // Route to target shard
             match SHARD_REGISTER[shard as usize] {
-                Some(ref shard_uri) => {
+                Some(ref shard_register) => {
+                    // Pick shard backend for this request (hashing on cache \
+                    //   key if any, or on request path otherwise)
+                    let backend = shard_register.balance.pick(key.unwrap_or(uri.path()));
+
+                    let shard_uri = &shard_register.backends[backend].uri;
+
                     // Format the original request URI into the downstream API server URI
                     let mut tunnel_uri = format!(
                         "{}://{}{}",
@@ -96,23 +246,36 @@ impl ProxyTunnel {
                         uri.path()
                     );
 
-                    if let Some(query) = uri.query() {
+                    if let Some(query) = Self::acquire_query(shard, uri) {
                         tunnel_uri.push_str("?");
-                        tunnel_uri.push_str(query);
+                        tunnel_uri.push_str(&query);
                     }
 
                     match tunnel_uri.parse::<Uri>() {
-                        Ok(tunnel_uri) => TUNNEL_CLIENT.with(|client| {
-                            // Dispatch original request to downstream API server
-                            Box::pin(Self::dispatch_to(
-                                client.clone(),
-                                method.clone(),
-                                tunnel_uri,
-                                uri.to_string(),
-                                headers.clone(),
-                                body,
-                            )) as ProxyTunnelFuture
-                        }),
+                        Ok(tunnel_uri) => {
+                            match TUNNEL_CLIENTS.with(|clients| {
+                                clients[shard as usize]
+                                    .as_ref()
+                                    .map(|clients| clients[backend].clone())
+                            }) {
+                                // Dispatch original request to downstream API server
+                                Some(client) => Box::pin(Self::dispatch_to(
+                                    ProxyTunnelTarget {
+                                        client,
+                                        _in_flight: shard_register.balance.acquire(backend),
+                                    },
+                                    method.clone(),
+                                    tunnel_uri,
+                                    uri.to_string(),
+                                    headers.clone(),
+                                    body,
+                                    validators.cloned(),
+                                )),
+                                None => Box::pin(async move {
+                                    Err(Self::make_proxy_err("shard client not configured"))
+                                }),
+                            }
+                        }
                         Err(_) => {
                             Box::pin(async move { Err(Self::make_proxy_err("invalid tunnel uri")) })
                         }
@@ -127,13 +290,44 @@ impl ProxyTunnel {
     }
 
     async fn dispatch_to(
-        client: Client<HttpConnector, ProxyTunnelRequestBody>,
+        target: ProxyTunnelTarget,
         method: Method,
         tunnel_uri: Uri,
         original_uri: String,
-        headers: HeaderMap,
-        body: Incoming,
+        mut headers: HeaderMap,
+        body: Option<Incoming>,
+        validators: Option<CacheStoreValidators>,
     ) -> Result<Response<Incoming>, ProxyServeError> {
+        // Strip hop-by-hop headers, as they only apply to the inbound \
+        //   client connection (not to the downstream API server connection)
+        HeaderJanitor::clean_request(&mut headers);
+
+        // Fetch full response for range requests, as partial responses are \
+        //   sliced from full responses (so that they can be cached)
+        if method == Method::GET {
+            headers.remove(header::RANGE);
+            headers.remove(header::IF_RANGE);
+        }
+
+        // Fetch full response for conditional requests, as 'Not Modified' \
+        //   responses are derived from full responses by Bloom (so that \
+        //   they can be cached)
+        if method == Method::GET || method == Method::HEAD {
+            headers.remove(header::IF_NONE_MATCH);
+            headers.remove(header::IF_MODIFIED_SINCE);
+        }
+
+        // Fetch identity responses if responses get encoded by Bloom, so \
+        //   that they can be cached once for all encodings
+        if APP_CONF.proxy.compress_responses == true {
+            headers.remove(header::ACCEPT_ENCODING);
+        }
+
+        // Revalidate stale cache using its own validators? (if any)
+        if let Some(validators) = validators {
+            Self::set_validators(&mut headers, validators);
+        }
+
         // Collect request body for methods that can come with a body.
         // Notice #1: buffer body upfront by draining its bytes, so that we can send it one-shot \
         //   to the downstream API server. The goal is to decouple the slow inbound client \
@@ -141,18 +335,23 @@ impl ProxyTunnel {
         //   resources (NGINX proxy does that too).
         // Notice #2: HTTP DELETE is not forbidden per-spec to hold a request body, even if it is \
         //   not commonly used. Hence why we forward it.
-        let body_bytes: Option<Bytes> = if matches!(
-            method,
-            Method::POST | Method::PATCH | Method::PUT | Method::DELETE
-        ) {
-            Some(
-                body.collect()
-                    .await
-                    .map_err(|err| -> ProxyServeError { Box::new(err) })?
-                    .to_bytes(),
-            )
-        } else {
-            None
+        // Notice #3: requests that are not issued by a client (eg. background \
+        //   cache refreshes) do not come with a body.
+        let body_bytes: Option<Bytes> = match body {
+            Some(body)
+                if matches!(
+                    method,
+                    Method::POST | Method::PATCH | Method::PUT | Method::DELETE
+                ) =>
+            {
+                Some(
+                    body.collect()
+                        .await
+                        .map_err(|err| -> ProxyServeError { Box::new(err) })?
+                        .to_bytes(),
+                )
+            }
+            _ => None,
         };
 
         // Send request to request log? (if logger is enabled)
@@ -178,13 +377,84 @@ impl ProxyTunnel {
         *tunnel_req.uri_mut() = tunnel_uri;
         *tunnel_req.headers_mut() = headers;
 
-        client
+        target
+            .client
             .request(tunnel_req)
             .await
             .map_err(|err| -> ProxyServeError { Box::new(err) })
     }
 
+    fn acquire_query(shard: u8, uri: &Uri) -> Option<Cow<'_, str>> {
+        // Forward normalized query if configured, so that the request sent \
+        //   to the API is consistent with its cache key
+        match APP_CONF.proxy.find_shard(shard) {
+            Some(shard_config) if shard_config.cache_key.forward_query == true => {
+                CacheRoute::normalize_query(uri.query(), &shard_config.cache_key)
+                    .filter(|query| query.is_empty() == false)
+            }
+            _ => uri.query().map(Cow::Borrowed),
+        }
+    }
+
+    fn set_validators(headers: &mut HeaderMap, validators: CacheStoreValidators) {
+        // Only replace client conditional headers if there are validators, \
+        //   otherwise the API response would not be related to stored cache
+        if validators.is_empty() == true {
+            return;
+        }
+
+        headers.remove(header::IF_NONE_MATCH);
+        headers.remove(header::IF_MODIFIED_SINCE);
+
+        if let Some(etag) = validators.etag {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = validators.last_modified {
+            headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
     fn make_proxy_err(msg: &'static str) -> ProxyServeError {
         Box::new(std::io::Error::new(std::io::ErrorKind::Other, msg))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn it_sets_validators() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"a1b2\""));
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Thu, 01 Jan 1970 00:00:00 GMT"),
+        );
+
+        ProxyTunnel::set_validators(
+            &mut headers,
+            CacheStoreValidators {
+                etag: Some(HeaderValue::from_static("\"v1\"")),
+                last_modified: None,
+            },
+        );
+
+        assert_eq!(headers.get(header::IF_NONE_MATCH).unwrap(), "\"v1\"");
+        assert!(headers.get(header::IF_MODIFIED_SINCE).is_none());
+    }
+
+    #[test]
+    fn it_keeps_conditional_headers_without_validators() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"a1b2\""));
+
+        ProxyTunnel::set_validators(&mut headers, CacheStoreValidators::default());
+
+        assert_eq!(headers.get(header::IF_NONE_MATCH).unwrap(), "\"a1b2\"");
+    }
+}
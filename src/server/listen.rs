@@ -4,12 +4,20 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use hyper::server::conn::http1;
-use hyper_util::rt::TokioIo;
-use tokio::net::TcpListener;
+use std::time::Duration;
+
+use hyper::rt::{Read, Write};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
 use tokio::runtime::Runtime;
+use tokio::time::timeout;
 
 use super::handle::ServerRequestHandle;
+use super::socket::ServerSocket;
+use super::tls::{ServerTls, ServerTlsAcceptor};
+use crate::config::config::ConfigInet;
 use crate::APP_CONF;
 
 pub struct ServerListenBuilder;
@@ -23,36 +31,121 @@ impl ServerListenBuilder {
 
 impl ServerListen {
     pub fn run(&self) {
-        let server_inet = APP_CONF.server.inet;
+        let server_inet = &APP_CONF.server.inet;
 
         Runtime::new()
             .expect("failed to create server runtime")
             .block_on(async {
-                let listener = TcpListener::bind(server_inet)
-                    .await
-                    .expect("failed to bind server tcp listener");
-
-                info!("listening on http://{}", server_inet);
-
-                loop {
-                    match listener.accept().await {
-                        Ok((stream, _)) => {
-                            let io = TokioIo::new(stream);
-
-                            tokio::spawn(async move {
-                                if let Err(err) = http1::Builder::new()
-                                    .serve_connection(io, ServerRequestHandle)
-                                    .await
-                                {
-                                    debug!("server client connection dropped: {}", err);
-                                }
-                            });
+                // Terminate TLS? (if configured)
+                let tls_acceptor = APP_CONF.server.tls.as_ref().map(|tls| {
+                    (
+                        ServerTls::acquire_acceptor(tls),
+                        Duration::from_secs(tls.handshake_timeout_seconds),
+                    )
+                });
+
+                let scheme = if tls_acceptor.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+
+                match server_inet {
+                    ConfigInet::Tcp(addr) => {
+                        let listener = TcpListener::bind(addr)
+                            .await
+                            .expect("failed to bind server tcp listener");
+
+                        info!("listening on {}://{}", scheme, server_inet);
+
+                        loop {
+                            match listener.accept().await {
+                                Ok((stream, _)) => Self::handle(stream, &tls_acceptor),
+                                Err(err) => error!("server accept error: {}", err),
+                            }
                         }
-                        Err(err) => {
-                            error!("server accept error: {}", err);
+                    }
+                    ConfigInet::Unix(path) => {
+                        ServerSocket::prepare(path);
+
+                        let listener =
+                            UnixListener::bind(path).expect("failed to bind server unix listener");
+
+                        ServerSocket::secure(
+                            path,
+                            APP_CONF.server.socket_permissions,
+                            APP_CONF.server.socket_owner.as_deref(),
+                        );
+
+                        info!("listening on {}://{}", scheme, server_inet);
+
+                        loop {
+                            match listener.accept().await {
+                                Ok((stream, _)) => Self::handle(stream, &tls_acceptor),
+                                Err(err) => error!("server accept error: {}", err),
+                            }
                         }
                     }
                 }
             });
     }
+
+    fn handle<S>(stream: S, tls_acceptor: &Option<(ServerTlsAcceptor, Duration)>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match tls_acceptor {
+            Some((tls_acceptor, handshake_timeout)) => {
+                // Notice: acquire the current acceptor before spawning, as it \
+                //   may get reloaded meanwhile.
+                let tls_acceptor = tls_acceptor.read().unwrap().clone();
+                let handshake_timeout = *handshake_timeout;
+
+                // Notice: the handshake is bounded in time, so that idle or \
+                //   slow clients cannot hold connections open forever.
+                tokio::spawn(async move {
+                    match timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                        Ok(Ok(tls_stream)) => Self::serve(TokioIo::new(tls_stream)).await,
+                        Ok(Err(err)) => {
+                            debug!("server tls handshake failed: {}", err);
+                        }
+                        Err(_) => {
+                            debug!("server tls handshake timed out");
+                        }
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(Self::serve(TokioIo::new(stream)));
+            }
+        }
+    }
+
+    async fn serve<I>(io: I)
+    where
+        I: Read + Write + Unpin + Send + 'static,
+    {
+        if let Err(err) = Self::make_builder()
+            .serve_connection(io, ServerRequestHandle)
+            .await
+        {
+            debug!("server client connection dropped: {}", err);
+        }
+    }
+
+    fn make_builder() -> auto::Builder<TokioExecutor> {
+        // Notice: HTTP/2 connections are detected from their preface, thus \
+        //   HTTP/1.1 and HTTP/2 (with prior knowledge) share the same port.
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+
+        builder.http1().max_headers(APP_CONF.proxy.max_headers);
+
+        builder
+            .http2()
+            .max_concurrent_streams(APP_CONF.server.http2_max_concurrent_streams)
+            .initial_stream_window_size(APP_CONF.server.http2_initial_stream_window_size)
+            .initial_connection_window_size(APP_CONF.server.http2_initial_connection_window_size);
+
+        builder
+    }
 }
@@ -7,3 +7,5 @@
 mod handle;
 
 pub mod listen;
+pub mod socket;
+pub mod tls;
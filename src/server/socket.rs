@@ -0,0 +1,142 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use nix::unistd::{Group, User};
+
+pub struct ServerSocket;
+
+impl ServerSocket {
+    pub fn prepare(path: &Path) {
+        // Remove socket left over by a previous run (if any), as binding \
+        //   would fail otherwise. Do not remove files that are not sockets.
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() == true {
+                debug!("removing stale socket: {:?}", path);
+
+                fs::remove_file(path).expect("could not remove stale socket");
+            }
+        }
+    }
+
+    pub fn secure(path: &Path, permissions: Option<u32>, owner: Option<&str>) {
+        if let Some(permissions) = permissions {
+            fs::set_permissions(path, Permissions::from_mode(permissions))
+                .expect("could not set socket permissions");
+        }
+
+        if let Some(owner) = owner {
+            let (user, group) = Self::resolve_owner(owner).expect("invalid socket owner");
+
+            chown(path, user, group).expect("could not set socket owner");
+        }
+    }
+
+    fn resolve_owner(owner: &str) -> Result<(Option<u32>, Option<u32>), ()> {
+        // Owner is formatted as 'user', 'user:group' or ':group', where \
+        //   users and groups are either names or numeric identifiers
+        let mut parts = owner.splitn(2, ':');
+
+        let user = match parts.next().unwrap_or("") {
+            "" => None,
+            user => Some(Self::resolve_user(user)?),
+        };
+        let group = match parts.next().unwrap_or("") {
+            "" => None,
+            group => Some(Self::resolve_group(group)?),
+        };
+
+        Ok((user, group))
+    }
+
+    fn resolve_user(user: &str) -> Result<u32, ()> {
+        if let Ok(uid) = user.parse() {
+            return Ok(uid);
+        }
+
+        match User::from_name(user) {
+            Ok(Some(user)) => Ok(user.uid.as_raw()),
+            _ => {
+                error!("socket owner user not found: {}", user);
+
+                Err(())
+            }
+        }
+    }
+
+    fn resolve_group(group: &str) -> Result<u32, ()> {
+        if let Ok(gid) = group.parse() {
+            return Ok(gid);
+        }
+
+        match Group::from_name(group) {
+            Ok(Some(group)) => Ok(group.gid.as_raw()),
+            _ => {
+                error!("socket owner group not found: {}", group);
+
+                Err(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixListener;
+
+    use nix::unistd::{getgid, getuid};
+
+    #[test]
+    fn it_resolves_owner() {
+        assert_eq!(ServerSocket::resolve_owner("1000"), Ok((Some(1000), None)));
+        assert_eq!(
+            ServerSocket::resolve_owner("1000:33"),
+            Ok((Some(1000), Some(33)))
+        );
+        assert_eq!(ServerSocket::resolve_owner(":33"), Ok((None, Some(33))));
+
+        // Resolve names of the current user and group (which may be missing \
+        //   on some hosts)
+        let (uid, gid) = (getuid(), getgid());
+
+        if let (Ok(Some(user)), Ok(Some(group))) = (User::from_uid(uid), Group::from_gid(gid)) {
+            assert_eq!(
+                ServerSocket::resolve_owner(&format!("{}:{}", user.name, group.name)),
+                Ok((Some(uid.as_raw()), Some(gid.as_raw())))
+            );
+        }
+
+        assert!(ServerSocket::resolve_owner("bloom-test-missing").is_err());
+    }
+
+    #[test]
+    fn it_prepares_and_secures_socket() {
+        let path = std::env::temp_dir().join(format!("bloom-test-{}.sock", std::process::id()));
+
+        // Notice: the socket file is left over once the listener is dropped
+        drop(UnixListener::bind(&path).unwrap());
+
+        ServerSocket::prepare(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+
+        ServerSocket::secure(&path, Some(0o600), None);
+
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        drop(listener);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
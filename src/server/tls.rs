@@ -0,0 +1,355 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::config::ConfigServerTls;
+
+pub struct ServerTls;
+
+pub type ServerTlsAcceptor = Arc<RwLock<TlsAcceptor>>;
+
+impl ServerTls {
+    pub fn acquire_acceptor(tls: &'static ConfigServerTls) -> ServerTlsAcceptor {
+        let acceptor = Arc::new(RwLock::new(
+            Self::make_acceptor(tls).expect("invalid server tls configuration"),
+        ));
+
+        Self::spawn_reload(tls, acceptor.clone());
+
+        acceptor
+    }
+
+    pub fn make_acceptor(tls: &ConfigServerTls) -> Result<TlsAcceptor, ()> {
+        let provider = Arc::new(ring::default_provider());
+
+        let certificates = Self::load_certificates(&tls.certificate)?;
+
+        let private_key = Self::load_private_key(&tls.private_key)?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| error!("could not configure tls protocols because: {}", err))?;
+
+        // Require clients to present a certificate signed by the client CA? \
+        //   (mutual TLS)
+        let builder = match tls.client_ca {
+            Some(ref client_ca_path) => builder
+                .with_client_cert_verifier(Self::make_client_verifier(client_ca_path, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certificates, private_key)
+            .map_err(|err| error!("could not use tls certificate because: {}", err))?;
+
+        // Notice: HTTP/2 connections are also detected from their preface, \
+        //   though clients only send it over TLS if negotiated with ALPN.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn make_client_verifier(
+        client_ca_path: &Path,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, ()> {
+        let mut roots = RootCertStore::empty();
+
+        for certificate in Self::load_certificates(client_ca_path)? {
+            roots.add(certificate).map_err(|err| {
+                error!(
+                    "could not add tls client ca: {:?} because: {}",
+                    client_ca_path, err
+                );
+            })?;
+        }
+
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(|err| error!("could not configure tls client ca because: {}", err))
+    }
+
+    pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, ()> {
+        PrivateKeyDer::from_pem_file(path).map_err(|err| {
+            error!(
+                "could not load tls private key: {:?} because: {}",
+                path, err
+            );
+        })
+    }
+
+    pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ()> {
+        let certificates = CertificateDer::pem_file_iter(path)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| {
+                error!(
+                    "could not load tls certificates: {:?} because: {}",
+                    path, err
+                );
+            })?;
+
+        if certificates.is_empty() == true {
+            error!("no tls certificate found in: {:?}", path);
+
+            return Err(());
+        }
+
+        Ok(certificates)
+    }
+
+    fn spawn_reload(tls: &'static ConfigServerTls, acceptor: ServerTlsAcceptor) {
+        let mut hangups = signal(SignalKind::hangup()).expect("failed to listen for sighup");
+
+        // Reload certificates upon 'SIGHUP' (eg. after they got renewed). \
+        //   Established connections keep using the previous certificates.
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("got sighup, reloading tls certificates");
+
+                match Self::make_acceptor(tls) {
+                    Ok(reloaded_acceptor) => {
+                        *acceptor.write().unwrap() = reloaded_acceptor;
+
+                        info!("reloaded tls certificates");
+                    }
+                    Err(_) => {
+                        error!("could not reload tls certificates, keeping previous ones");
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use rustls::ClientConfig;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    pub struct TestAuthority {
+        params: CertificateParams,
+        key: KeyPair,
+        pub pem: String,
+    }
+
+    pub fn make_authority() -> TestAuthority {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let key = KeyPair::generate().unwrap();
+        let pem = params.clone().self_signed(&key).unwrap().pem();
+
+        TestAuthority { params, key, pem }
+    }
+
+    pub fn make_signed(authority: &TestAuthority, name: &str) -> (String, String) {
+        let issuer = authority
+            .params
+            .clone()
+            .self_signed(&authority.key)
+            .unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let certificate = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &issuer, &authority.key)
+            .unwrap();
+
+        (certificate.pem(), key.serialize_pem())
+    }
+
+    pub fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("bloom-test-tls-{}-{}", name, std::process::id()));
+
+        fs::create_dir_all(&directory).unwrap();
+
+        for (file_name, contents) in files {
+            fs::write(directory.join(file_name), contents).unwrap();
+        }
+
+        directory
+    }
+
+    fn make_connector(authority: &TestAuthority, client: Option<(String, String)>) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+
+        roots
+            .add(CertificateDer::from_pem_slice(authority.pem.as_bytes()).unwrap())
+            .unwrap();
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+
+        let mut config = match client {
+            Some((certificate, key)) => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_slice(certificate.as_bytes()).unwrap()],
+                    PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        config.alpn_protocols = vec![b"h2".to_vec()];
+
+        TlsConnector::from(Arc::new(config))
+    }
+
+    pub async fn handshake(
+        acceptor: TlsAcceptor,
+        connector: TlsConnector,
+    ) -> Result<Option<Vec<u8>>, ()> {
+        let (client_io, server_io) = duplex(16384);
+
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server_io).await.map_err(|_| ())?;
+            let mut buffer = [0; 4];
+
+            stream.read_exact(&mut buffer).await.map_err(|_| ())?;
+            stream.write_all(&buffer).await.map_err(|_| ())?;
+            stream.flush().await.map_err(|_| ())
+        });
+
+        let client = async move {
+            let mut stream = connector
+                .connect(ServerName::try_from("localhost").unwrap(), client_io)
+                .await
+                .map_err(|_| ())?;
+            let mut buffer = [0; 4];
+
+            stream.write_all(b"ping").await.map_err(|_| ())?;
+            stream.read_exact(&mut buffer).await.map_err(|_| ())?;
+
+            Ok(stream.get_ref().1.alpn_protocol().map(|alpn| alpn.to_vec()))
+        };
+
+        let client_result = client.await;
+
+        server.await.unwrap().and(client_result)
+    }
+
+    #[tokio::test]
+    async fn it_accepts_self_signed_certificate() {
+        let authority = make_authority();
+        let (certificate, key) = make_signed(&authority, "localhost");
+
+        let directory = write_files(
+            "plain",
+            &[("server.crt", &certificate), ("server.key", &key)],
+        );
+
+        let acceptor = ServerTls::make_acceptor(&ConfigServerTls {
+            certificate: directory.join("server.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: None,
+            handshake_timeout_seconds: 10,
+        })
+        .unwrap();
+
+        assert_eq!(
+            handshake(acceptor, make_connector(&authority, None)).await,
+            Ok(Some(b"h2".to_vec()))
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_requires_client_certificate_with_client_ca() {
+        let authority = make_authority();
+        let client_authority = make_authority();
+
+        let (certificate, key) = make_signed(&authority, "localhost");
+
+        let directory = write_files(
+            "mutual",
+            &[
+                ("server.crt", &certificate),
+                ("server.key", &key),
+                ("client-ca.crt", &client_authority.pem),
+            ],
+        );
+
+        let config = ConfigServerTls {
+            certificate: directory.join("server.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: Some(directory.join("client-ca.crt")),
+            handshake_timeout_seconds: 10,
+        };
+
+        assert!(handshake(
+            ServerTls::make_acceptor(&config).unwrap(),
+            make_connector(&authority, None)
+        )
+        .await
+        .is_err());
+
+        assert!(handshake(
+            ServerTls::make_acceptor(&config).unwrap(),
+            make_connector(&authority, Some(make_signed(&authority, "client")))
+        )
+        .await
+        .is_err());
+
+        assert!(handshake(
+            ServerTls::make_acceptor(&config).unwrap(),
+            make_connector(&authority, Some(make_signed(&client_authority, "client")))
+        )
+        .await
+        .is_ok());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn it_fails_on_invalid_certificate() {
+        let directory = write_files(
+            "invalid",
+            &[("server.crt", "invalid"), ("server.key", "invalid")],
+        );
+
+        assert!(ServerTls::make_acceptor(&ConfigServerTls {
+            certificate: directory.join("server.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: None,
+            handshake_timeout_seconds: 10,
+        })
+        .is_err());
+
+        assert!(ServerTls::make_acceptor(&ConfigServerTls {
+            certificate: directory.join("missing.crt"),
+            private_key: directory.join("server.key"),
+            client_ca: None,
+            handshake_timeout_seconds: 10,
+        })
+        .is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}